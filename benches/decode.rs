@@ -0,0 +1,154 @@
+//! Time and allocations spent decoding READY-sized messages. Run with
+//! `cargo bench --bench decode`, and `cargo test --bench decode` for the
+//! allocation counts.
+
+#![feature(test)]
+
+extern crate bytes;
+extern crate discord_native_win;
+extern crate test;
+extern crate tokio_io;
+
+use discord_native_win::discord::gateway::websocket::ServerEncoder;
+use discord_native_win::discord::gateway::websocket::{ClientDecoder, Frame, Message};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::usize;
+
+use bytes::BytesMut;
+use test::Bencher;
+use tokio_io::codec::{Decoder, Encoder};
+
+/// READY for an account in a few hundred guilds
+const READY_SIZE: usize = 512 * 1024;
+
+/// Counts allocations made by the current thread, so tests running in
+/// parallel don't skew each other
+struct Counting;
+
+thread_local!(static ALLOCATIONS: Cell<usize> = Cell::new(0));
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+fn ready_payload() -> String {
+    let guild = r#"{"id":"41771983423143937","name":"Discord Developers","unavailable":false,"members":[{"user":{"id":"80351110224678912","username":"Nelly","discriminator":"1337"},"roles":[],"joined_at":"2015-04-26T06:26:56.936000+00:00"}]},"#;
+
+    let mut payload = String::from(r#"{"t":"READY","s":1,"op":0,"d":{"v":6,"guilds":["#);
+    while payload.len() < READY_SIZE {
+        payload.push_str(guild);
+    }
+    payload.pop();
+    payload.push_str("]}}");
+    payload
+}
+
+/// The payload as the gateway sends it, in frames of `fragment` bytes
+fn encoded(payload: &str, fragment: usize) -> BytesMut {
+    let mut encoder = ServerEncoder::with_fragment_size(fragment);
+    let mut buf = BytesMut::new();
+    encoder
+        .encode(Message::Text(payload.into()), &mut buf)
+        .unwrap();
+    buf
+}
+
+fn decode(decoder: &mut ClientDecoder, mut buf: BytesMut) -> String {
+    match decoder.decode(&mut buf) {
+        Ok(Some(Message::Text(text))) => text,
+        other => panic!("Expected the payload back, got {:?}", other),
+    }
+}
+
+/// How frames were decoded before they were split off the read buffer:
+/// every payload was copied out, and the rest of the buffer with it
+fn decode_copying(mut buf: BytesMut) -> String {
+    let mut payload = Vec::new();
+    while let Some((_, len)) = Frame::is_complete(&buf) {
+        // Server frames are unmasked, so only the length varies the header
+        let hlen = match buf[1] & 0x7f {
+            126 => 4,
+            127 => 10,
+            _ => 2,
+        };
+        let rest = buf.split_off(len);
+        payload.extend(buf[hlen..].to_vec());
+        buf = rest;
+    }
+    String::from_utf8(payload).unwrap()
+}
+
+fn bench_decode(b: &mut Bencher, fragment: usize) {
+    let frames = encoded(&ready_payload(), fragment);
+    let mut decoder = ClientDecoder::with_limit(READY_SIZE * 2);
+    b.bytes = frames.len() as u64;
+    b.iter(|| decode(&mut decoder, frames.clone()));
+}
+
+#[bench]
+fn ready_whole(b: &mut Bencher) {
+    bench_decode(b, usize::MAX);
+}
+
+#[bench]
+fn ready_fragmented(b: &mut Bencher) {
+    bench_decode(b, 4096);
+}
+
+#[bench]
+fn ready_fragmented_copying(b: &mut Bencher) {
+    let frames = encoded(&ready_payload(), 4096);
+    b.bytes = frames.len() as u64;
+    b.iter(|| decode_copying(frames.clone()));
+}
+
+#[test]
+fn ready_allocations() {
+    let payload = ready_payload();
+    for &fragment in &[usize::MAX, 4096] {
+        let frames = encoded(&payload, fragment);
+        let count = frames.len() / fragment.min(frames.len());
+
+        let mut decoder = ClientDecoder::with_limit(READY_SIZE * 2);
+        let buf = frames.clone();
+        let before = allocations();
+        let text = decode(&mut decoder, buf);
+        let decoding = allocations() - before;
+        assert_eq!(text, payload);
+
+        let buf = frames.clone();
+        let before = allocations();
+        let text = decode_copying(buf);
+        let copying = allocations() - before;
+        assert_eq!(text, payload);
+
+        println!(
+            "{} frames: {} allocations, {} when copying",
+            count, decoding, copying
+        );
+        // The payload is copied into the String once, and fragmented ones
+        // are joined once, whatever the number of frames
+        assert!(decoding <= 8, "{} allocations", decoding);
+    }
+}
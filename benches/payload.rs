@@ -0,0 +1,78 @@
+//! Handing a READY packet to several handlers. Compares decoding the `d`
+//! field once and sharing it with the two designs before it: every handler
+//! parsing the whole packet, and every handler getting its own clone.
+
+#![feature(test)]
+
+extern crate discord_native_win;
+extern crate serde_json;
+extern crate test;
+
+use discord_native_win::discord::gateway::packets::{DataOnlyPacket, IgnoreData, Packet, Ready};
+use discord_native_win::discord::gateway::payload::Payload;
+
+use std::fmt::Write;
+
+use test::{black_box, Bencher};
+
+/// Handlers interested in READY, as with the session, the cache, a logger
+/// and the user's own
+const HANDLERS: usize = 4;
+
+fn ready_packet() -> String {
+    let mut guilds = String::new();
+    for i in 0..1000u64 {
+        if i > 0 {
+            guilds.push(',');
+        }
+        write!(
+            guilds,
+            r#"{{"id":"{}","unavailable":true}}"#,
+            41771983423143937 + i
+        ).unwrap();
+    }
+
+    format!(
+        r#"{{"t":"READY","s":1,"op":0,"d":{{"v":6,"user":{{"id":"80351110224678912","username":"Nelly","discriminator":"1337","avatar":null,"verified":true,"email":"nelly@discordapp.com"}},"private_channels":[],"guilds":[{}],"session_id":"f4dcb5bd8c1b2a1b0e8d3b0f6a1d8c2e","_trace":["gateway-prd-main-abcd"]}}}}"#,
+        guilds
+    )
+}
+
+#[bench]
+fn shared(b: &mut Bencher) {
+    let text = ready_packet();
+    b.bytes = text.len() as u64;
+    b.iter(|| {
+        let payload = Payload::parse(&text).unwrap();
+        for _ in 0..HANDLERS {
+            black_box(payload.decode::<Ready>().unwrap());
+        }
+    });
+}
+
+#[bench]
+fn cloned_per_handler(b: &mut Bencher) {
+    let text = ready_packet();
+    b.bytes = text.len() as u64;
+    b.iter(|| {
+        let payload = Payload::parse(&text).unwrap();
+        let ready = payload.decode::<Ready>().unwrap();
+        for _ in 0..HANDLERS {
+            black_box((*ready).clone());
+        }
+    });
+}
+
+#[bench]
+fn parsed_per_handler(b: &mut Bencher) {
+    let text = ready_packet();
+    b.bytes = text.len() as u64;
+    b.iter(|| {
+        let envelope: Packet<IgnoreData> = serde_json::from_str(&text).unwrap();
+        black_box(envelope.sequence);
+        for _ in 0..HANDLERS {
+            let packet: DataOnlyPacket<Ready> = serde_json::from_str(&text).unwrap();
+            black_box(packet.payload);
+        }
+    });
+}
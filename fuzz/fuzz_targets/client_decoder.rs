@@ -0,0 +1,43 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate discord_native_win;
+extern crate tokio_io;
+
+use bytes::BytesMut;
+use discord_native_win::discord::gateway::websocket::{ClientDecoder, DeflateConfig};
+use tokio_io::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the decoder's mode and how the rest arrives
+    let (&mode, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    let mut decoder = ClientDecoder::with_limit(1 << 16).with_frame_limit(1 << 12);
+    if mode & 1 != 0 {
+        decoder = decoder.streaming();
+    }
+    if mode & 2 != 0 {
+        decoder = decoder.with_deflate(&DeflateConfig {
+            client_no_context_takeover: mode & 4 != 0,
+            server_no_context_takeover: mode & 8 != 0,
+            server_max_window_bits: 15,
+        });
+    }
+    let chunk_size = 1 + (mode >> 4) as usize * 64;
+
+    let mut buf = BytesMut::new();
+    for chunk in data.chunks(chunk_size) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
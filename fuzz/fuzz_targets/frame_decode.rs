@@ -0,0 +1,32 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate discord_native_win;
+
+use bytes::BytesMut;
+use discord_native_win::discord::gateway::websocket::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    let (flags, len) = match Frame::is_complete(&buf) {
+        Some(info) => info,
+        None => return,
+    };
+
+    let frame = Frame::decode(&mut buf);
+    assert_eq!(frame.flags, flags);
+    assert_eq!(buf.len(), data.len() - len);
+    // Lengths may be encoded in more bytes than needed, but never fewer
+    assert!(frame.frame_size() <= len);
+
+    // Encoding the frame again, with its mask, has to give back the payload
+    let mut encoded = BytesMut::with_capacity(frame.frame_size());
+    frame.encode(&mut encoded);
+    assert_eq!(encoded.len(), frame.frame_size());
+    let again = Frame::decode(&mut encoded);
+    assert!(encoded.is_empty());
+    assert_eq!(again.flags, frame.flags);
+    assert_eq!(again.masking_key, frame.masking_key);
+    assert_eq!(again.payload, frame.payload);
+});
@@ -0,0 +1,26 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bytes;
+extern crate discord_native_win;
+
+use bytes::BytesMut;
+use discord_native_win::discord::gateway::websocket::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    // Once a prefix holds a complete frame, every longer one holds the same
+    let mut complete = None;
+    for end in 0..=data.len() {
+        let buf = BytesMut::from(&data[..end]);
+        match (Frame::is_complete(&buf), complete) {
+            (Some((_, len)), None) => {
+                assert!(len <= end);
+                assert!(Frame::lengths(&buf).is_some());
+                complete = Some(len);
+            }
+            (Some((_, len)), Some(first)) => assert_eq!(len, first),
+            (None, Some(first)) => panic!("Frame of {} bytes stopped being complete", first),
+            (None, None) => (),
+        }
+    }
+});
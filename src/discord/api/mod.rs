@@ -1,3 +1,4 @@
+use discord::proxy::{ProxyConnector, ProxySettings};
 use error::{ApiError, DResult, Error};
 
 use std::sync::RwLock;
@@ -5,8 +6,9 @@ use std::sync::RwLock;
 use futures::prelude::*;
 use http::uri::{Authority, Parts, PathAndQuery, Scheme, Uri};
 use hyper::body::Payload;
-use hyper::client::{Client, HttpConnector};
+use hyper::client::Client;
 use hyper_tls::HttpsConnector;
+use native_tls::TlsConnector;
 use serde::de;
 use serde_json;
 
@@ -22,6 +24,12 @@ pub fn dispose() {
     CLIENT.dispose();
 }
 
+/// Sends REST requests through `proxy` instead of the one in the
+/// environment. Pass the same settings as the gateway's `ConnectSettings`.
+pub fn set_proxy(proxy: ProxySettings) {
+    CLIENT.set_proxy(proxy);
+}
+
 pub fn get_data<T>(endpoint: &str) -> impl Future<Item = T, Error = Error>
 where
     T: for<'de> de::Deserialize<'de>,
@@ -58,18 +66,23 @@ where
     Ok(serde_json::from_slice(&data)?)
 }
 
+pub type HttpsClient = Client<HttpsConnector<ProxyConnector>>;
+
 pub struct ClientWrapper {
-    client: RwLock<Option<Client<HttpsConnector<HttpConnector>>>>,
+    client: RwLock<Option<HttpsClient>>,
+    /// Read from the environment when the client is first built, unless set
+    proxy: RwLock<Option<ProxySettings>>,
 }
 
 impl ClientWrapper {
     fn new() -> Self {
         ClientWrapper {
             client: RwLock::new(None),
+            proxy: RwLock::new(None),
         }
     }
 
-    pub fn with<F, R>(&self, f: F) -> R where F: FnOnce(&Client<HttpsConnector<HttpConnector>>) -> R {
+    pub fn with<F, R>(&self, f: F) -> R where F: FnOnce(&HttpsClient) -> R {
         if let Some(ref client) = *self.client.read().unwrap() {
             return f(client);
         }
@@ -79,12 +92,23 @@ impl ClientWrapper {
             return f(client);
         }
 
-        let client = Client::builder().build(HttpsConnector::new(4).unwrap());
+        let settings = self.proxy.read().unwrap().clone();
+        let proxy = ProxyConnector::new(settings.unwrap_or_else(ProxySettings::from_env));
+        let tls = TlsConnector::builder().and_then(|b| b.build()).unwrap();
+        let client = Client::builder().build(HttpsConnector::from((proxy, tls)));
         let result = f(&client);
         *slot = Some(client);
         result
     }
 
+    /// Takes effect for requests made after it returns. The client is
+    /// rebuilt, so pooled connections through the old proxy are dropped.
+    pub fn set_proxy(&self, proxy: ProxySettings) {
+        let mut client = self.client.write().unwrap();
+        *self.proxy.write().unwrap() = Some(proxy);
+        *client = None;
+    }
+
     fn dispose(&self) {
         if let Ok(mut client) = self.client.write() {
             *client = None;
@@ -1,12 +1,129 @@
-use discord::gateway::websocket::{Error, Message, Reader};
+use discord::gateway::websocket::{Error, Message, OpCode, Reader};
 use discord::gateway::GatewayMessage;
 
+use std::mem;
+
 use flate2::{self, FlushDecompress, Status};
 use futures::prelude::*;
 
+/// Room added to the inflate buffer whenever it runs out
+const INFLATE_CHUNK: usize = 16 * 1024;
+
+/// Turns websocket messages into gateway packets, inflating Discord's
+/// zlib-stream. Messages read in streaming mode are inflated chunk by chunk
+/// as they arrive, so a compressed READY is never held whole.
 pub struct MessageDeflater {
-    pub reader: Reader,
-    pub zlib: flate2::Decompress,
+    reader: Reader,
+    zlib: flate2::Decompress,
+    /// Output of the zlib stream since the last complete packet
+    inflated: Vec<u8>,
+    /// The kind of message being streamed, if any
+    streaming: Option<OpCode>,
+    /// Payload of a streamed text message
+    text: Vec<u8>,
+    /// Last bytes of a streamed binary message, to spot the flush suffix
+    tail: Vec<u8>,
+}
+
+impl MessageDeflater {
+    pub fn new(reader: Reader) -> MessageDeflater {
+        MessageDeflater {
+            reader,
+            zlib: flate2::Decompress::new(true),
+            inflated: Vec::new(),
+            streaming: None,
+            text: Vec::new(),
+            tail: Vec::with_capacity(ZLIB_SUFFIX.len() * 2),
+        }
+    }
+
+    /// Feeds `data` to the zlib stream, appending everything it inflates to
+    /// `inflated`
+    fn inflate(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        loop {
+            if self.inflated.capacity() - self.inflated.len() < INFLATE_CHUNK {
+                self.inflated.reserve(INFLATE_CHUNK);
+            }
+
+            let (total_in, total_out) = (self.zlib.total_in(), self.zlib.total_out());
+            let flush = FlushDecompress::None;
+            match self.zlib.decompress_vec(data, &mut self.inflated, flush) {
+                Ok(Status::Ok) | Ok(Status::BufError) | Ok(Status::StreamEnd) => (),
+                Err(_) => return Err(Error::BadCompression),
+            }
+            let consumed = (self.zlib.total_in() - total_in) as usize;
+            let produced = self.zlib.total_out() - total_out;
+            data = &data[consumed..];
+
+            // zlib only stops short of the input when the output is full
+            if data.is_empty() && self.inflated.len() < self.inflated.capacity() {
+                return Ok(());
+            }
+            if consumed == 0 && produced == 0 {
+                return Err(Error::BadCompression);
+            }
+        }
+    }
+
+    /// Takes the text inflated so far, once the server has flushed a packet
+    fn take_packet(&mut self) -> Result<GatewayMessage, Error> {
+        let inflated = mem::replace(&mut self.inflated, Vec::new());
+        let text = String::from_utf8(inflated).map_err(|_| Error::TextFrameNotUtf8)?;
+        debug!("gateway packet decompressed");
+        Ok(GatewayMessage::Packet(text))
+    }
+
+    fn track_tail(&mut self, data: &[u8]) {
+        let keep = ZLIB_SUFFIX.len();
+        let start = data.len().saturating_sub(keep);
+        self.tail.extend_from_slice(&data[start..]);
+        let excess = self.tail.len().saturating_sub(keep);
+        self.tail.drain(..excess);
+    }
+
+    /// Handles one message, yielding a packet once one is complete
+    fn handle(&mut self, message: Message) -> Result<Option<GatewayMessage>, Error> {
+        let packet = match message {
+            Message::Binary(data) => {
+                if !is_zlib(&data) {
+                    return Ok(Some(GatewayMessage::OtherFrame(Message::Binary(data))));
+                }
+                self.inflate(&data)?;
+                Some(self.take_packet()?)
+            }
+            Message::Text(text) => Some(GatewayMessage::Packet(text)),
+            Message::Start(opcode) => {
+                self.streaming = Some(opcode);
+                self.tail.clear();
+                None
+            }
+            Message::Chunk(data) => {
+                match self.streaming {
+                    Some(OpCode::Binary) => {
+                        self.inflate(&data)?;
+                        self.track_tail(&data);
+                    }
+                    Some(_) => self.text.extend_from_slice(&data),
+                    None => return Err(Error::UnexpectedStream),
+                }
+                None
+            }
+            Message::End => match self.streaming.take() {
+                // A packet may span several messages, and is only complete
+                // once one ends with the flush suffix
+                Some(OpCode::Binary) if is_zlib(&self.tail) => Some(self.take_packet()?),
+                Some(OpCode::Binary) => None,
+                Some(_) => {
+                    let text = mem::replace(&mut self.text, Vec::new());
+                    let text = String::from_utf8(text).map_err(|_| Error::TextFrameNotUtf8)?;
+                    Some(GatewayMessage::Packet(text))
+                }
+                None => return Err(Error::UnexpectedStream),
+            },
+            frame => Some(GatewayMessage::OtherFrame(frame)),
+        };
+        Ok(packet)
+    }
 }
 
 impl Stream for MessageDeflater {
@@ -14,36 +131,16 @@ impl Stream for MessageDeflater {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<GatewayMessage>, Error> {
-        match self.reader.poll() {
-            Ok(Async::Ready(Some(Message::Binary(data)))) => {
-                if is_zlib(&data) {
-                    let mut buf = Vec::with_capacity(256);
-                    let flush = FlushDecompress::None;
-                    let res = self.zlib.decompress_vec(&data, &mut buf, flush);
-
-                    match res {
-                        Ok(Status::Ok) | Ok(Status::StreamEnd) => {
-                            let text = String::from_utf8(buf).map_err(|_| Error::TextFrameNotUtf8)?;
-                            debug!("gateway packet decompressed");
-                            Ok(Async::Ready(Some(GatewayMessage::Packet(text))))
-                        }
-                        _ => return Err(Error::BadCompression),
-                    }
-                } else {
-                    Ok(Async::Ready(Some(GatewayMessage::OtherFrame(
-                        Message::Binary(data),
-                    ))))
-                }
-            }
-            Ok(Async::Ready(Some(Message::Text(text)))) => {
-                Ok(Async::Ready(Some(GatewayMessage::Packet(text))))
-            }
-            Ok(Async::Ready(Some(frame))) => {
-                Ok(Async::Ready(Some(GatewayMessage::OtherFrame(frame))))
+        loop {
+            let message = match self.reader.poll()? {
+                Async::Ready(Some(message)) => message,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            if let Some(packet) = self.handle(message)? {
+                return Ok(Async::Ready(Some(packet)));
             }
-            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Err(e) => Err(e),
         }
     }
 }
@@ -52,3 +149,99 @@ const ZLIB_SUFFIX: [u8; 4] = [0, 0, 255, 255];
 fn is_zlib(data: &[u8]) -> bool {
     data.len() >= 4 && &data[data.len() - 4..] == &ZLIB_SUFFIX
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MessageDeflater;
+    use discord::gateway::websocket::{Error, Message, OpCode};
+    use discord::gateway::GatewayMessage;
+
+    use bytes::Bytes;
+    use flate2::{Compress, Compression, FlushCompress};
+    use futures::prelude::*;
+    use futures::stream;
+
+    /// Packets compressed on one zlib stream, each ending in a sync flush
+    fn compressed(packets: &[&str]) -> Vec<Vec<u8>> {
+        let mut zlib = Compress::new(Compression::default(), true);
+        packets
+            .iter()
+            .map(|packet| {
+                let mut out = Vec::with_capacity(packet.len() + 64);
+                zlib.compress_vec(packet.as_bytes(), &mut out, FlushCompress::Sync)
+                    .unwrap();
+                out
+            })
+            .collect()
+    }
+
+    fn packets(messages: Vec<Message>) -> Vec<String> {
+        let deflater = MessageDeflater::new(Box::new(stream::iter_ok::<_, Error>(messages)));
+        let packets = deflater.collect().wait().unwrap();
+        packets
+            .into_iter()
+            .map(|packet| match packet {
+                GatewayMessage::Packet(text) => text,
+                other => panic!("Expected a packet, got {:?}", other),
+            })
+            .collect()
+    }
+
+    /// Large enough to need several rounds of growing the inflate buffer
+    fn ready() -> String {
+        let guild = r#"{"id":"41771983423143937","unavailable":true},"#;
+        format!(r#"{{"op":0,"t":"READY","d":[{}{{}}]}}"#, guild.repeat(4000))
+    }
+
+    #[test]
+    fn whole_messages() {
+        let ready = ready();
+        let hello = r#"{"op":10,"d":{"heartbeat_interval":41250}}"#;
+        let messages = compressed(&[hello, &ready])
+            .into_iter()
+            .map(|data| Message::Binary(data.into()))
+            .collect();
+        assert_eq!(packets(messages), vec![hello.to_string(), ready]);
+    }
+
+    #[test]
+    fn streamed_messages() {
+        let ready = ready();
+        let hello = r#"{"op":10,"d":{"heartbeat_interval":41250}}"#;
+        let mut messages = vec![];
+        for data in compressed(&[hello, &ready]) {
+            messages.push(Message::Start(OpCode::Binary));
+            // Split so the suffix lands across chunks
+            for chunk in data.chunks(1021) {
+                messages.push(Message::Chunk(Bytes::from(chunk)));
+            }
+            messages.push(Message::End);
+        }
+        messages.push(Message::Start(OpCode::Text));
+        messages.push(Message::Chunk(Bytes::from(&b"{\"op\":11,"[..])));
+        messages.push(Message::Chunk(Bytes::from(&b"\"d\":null}"[..])));
+        messages.push(Message::End);
+
+        let expected = vec![
+            hello.to_string(),
+            ready,
+            r#"{"op":11,"d":null}"#.to_string(),
+        ];
+        assert_eq!(packets(messages), expected);
+    }
+
+    #[test]
+    fn packets_spanning_messages() {
+        let hello = r#"{"op":10,"d":{"heartbeat_interval":41250}}"#;
+        let data = compressed(&[hello]).remove(0);
+        let (first, second) = data.split_at(data.len() / 2);
+
+        let mut messages = vec![];
+        for part in &[first, second] {
+            messages.push(Message::Start(OpCode::Binary));
+            messages.push(Message::Chunk(Bytes::from(*part)));
+            messages.push(Message::End);
+        }
+        assert_eq!(packets(messages), vec![hello.to_string()]);
+    }
+}
@@ -1,18 +1,19 @@
 use discord::gateway::compression::MessageDeflater;
-use discord::gateway::packets::{DataOnlyPacket, IgnoreData, Packet, PacketData};
-use discord::gateway::websocket::{self, Message};
+use discord::gateway::packets::{Event, PacketData};
+use discord::gateway::payload::Payload;
+use discord::gateway::stream::{self, Backpressure, EventStream, Overflow};
+use discord::gateway::websocket::{self, CloseCode, Message};
 use discord::gateway::{GatewayMessage, Writer};
-use error::DResult;
-use logging::FutureLogExt;
+use error::{DResult, Error};
 
-use std::boxed::FnBox;
+use std::any::Any;
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 use std::time::SystemTime;
 
 use futures::prelude::*;
-use serde_json as json;
 use spin::{Mutex, RwLock};
 
 #[derive(Clone)]
@@ -21,120 +22,346 @@ pub struct Dispatcher {
 }
 
 impl Dispatcher {
-    pub fn handle_opcode<H, P>(&self, handler: H)
+    pub fn new() -> Dispatcher {
+        Dispatcher {
+            state: Arc::new(DispatcherState::default()),
+        }
+    }
+
+    /// Starts dispatching packets from a new connection. Installed handlers
+    /// are shared by every connection attached to this dispatcher.
+    ///
+    /// Returns the half of the writer not used for replying to pings, and a
+    /// future which must be polled to read the connection. It resolves once
+    /// the connection ends, with the Close frame if the server sent one.
+    pub fn attach(
+        &self,
+        reader: MessageDeflater,
+        writer: websocket::Writer,
+    ) -> (Writer, impl Future<Item = Option<CloseFrame>, Error = Error>) {
+        let (writer, handler_writer) = Writer::create(writer);
+        let handler_state = Arc::downgrade(&self.state);
+        let messages = handle_messages(handler_state, reader, handler_writer);
+        (writer, messages)
+    }
+
+    pub fn handle_opcode<H, P>(&self, handler: H) -> Subscription
     where
-        H: Fn(P) + Send + Sync + 'static,
-        P: PacketData,
+        H: Fn(Arc<P>) + Send + Sync + 'static,
+        P: PacketData + 'static,
     {
-        let raw_handler = move |data: &str| {
-            let payload = match json::from_str::<DataOnlyPacket<P>>(data) {
-                Ok(data) => data.payload,
-                Err(e) => return error!("Failed to deserialize gateway packet: {}", e),
-            };
+        let raw_handler = move |payload: &Payload| {
+            if let Some(payload) = decode_payload::<P>(payload) {
+                handler(payload);
+            }
+        };
 
-            handler(payload);
+        self.handle_opcode_raw(P::OPCODE, Box::new(raw_handler))
+    }
+
+    pub fn handle_event<H, P>(&self, handler: H) -> Subscription
+    where
+        H: Fn(Arc<P>) + Send + Sync + 'static,
+        P: PacketData + 'static,
+    {
+        let raw_handler = move |payload: &Payload| {
+            if let Some(payload) = decode_payload::<P>(payload) {
+                handler(payload);
+            }
         };
 
-        self.handle_opcode_raw(P::OPCODE, Box::new(raw_handler));
+        self.handle_event_raw(P::EVENT, Box::new(raw_handler))
     }
 
-    pub fn handle_event<H, P>(&self, handler: H)
+    /// Like `handle_opcode`, but the handler is removed after the first packet
+    pub fn handle_opcode_once<H, P>(&self, handler: H) -> Subscription
     where
-        H: Fn(P) + Send + Sync + 'static,
-        P: PacketData,
+        H: FnOnce(Arc<P>) + Send + 'static,
+        P: PacketData + 'static,
     {
-        let raw_handler = move |data: &str| {
-            let payload = match json::from_str::<DataOnlyPacket<P>>(data) {
-                Ok(data) => data.payload,
-                Err(e) => return error!("Failed to deserialize gateway packet: {}", e),
+        let list = self.state.opcode_list(P::OPCODE);
+        let id = self.state.next_id();
+        let handler = once_handler(handler, &list, id);
+        self.state.subscribe(&list, id, Arc::new(handler))
+    }
+
+    /// Like `handle_event`, but the handler is removed after the first event
+    pub fn handle_event_once<H, P>(&self, handler: H) -> Subscription
+    where
+        H: FnOnce(Arc<P>) + Send + 'static,
+        P: PacketData + 'static,
+    {
+        let list = self.state.event_list(P::EVENT);
+        let id = self.state.next_id();
+        let handler = once_handler(handler, &list, id);
+        self.state.subscribe(&list, id, Arc::new(handler))
+    }
+
+    /// Receives every dispatch packet, decoded into an `Event`. Events this
+    /// client doesn't know are passed along as `Event::Unknown`.
+    pub fn handle_any_event<H>(&self, handler: H) -> Subscription
+    where
+        H: Fn(Arc<Event>) + Send + Sync + 'static,
+    {
+        let raw_handler = move |payload: &Payload| {
+            let event = match payload.decode_event() {
+                Ok(event) => event,
+                Err(e) => return error!("Failed to deserialize gateway event: {}", e),
             };
 
-            handler(payload);
+            handler(event);
         };
 
-        self.handle_event_raw(P::EVENT, Box::new(raw_handler));
+        self.handle_any_event_raw(Box::new(raw_handler))
     }
 
-    pub fn handle_close<F>(&self, event: F)
+    /// Streams every `P` the gateway sends, buffering up to
+    /// `stream::DEFAULT_CAPACITY` packets and dropping the oldest after that.
+    pub fn events<P>(&self) -> EventStream<Arc<P>>
     where
-        F: FnOnce(u16, Option<String>) + Send + 'static,
+        P: PacketData + Send + Sync + 'static,
     {
-        *self.state.close_handler.lock() = Some(Box::new(event))
+        self.events_with(stream::DEFAULT_CAPACITY, Overflow::DropOldest)
+    }
+
+    /// Streams every `P` the gateway sends, buffering up to `capacity`
+    /// packets before applying `overflow`.
+    pub fn events_with<P>(&self, capacity: usize, overflow: Overflow) -> EventStream<Arc<P>>
+    where
+        P: PacketData + Send + Sync + 'static,
+    {
+        let (producer, receiver) = stream::channel(capacity, overflow);
+        if overflow == Overflow::Backpressure {
+            let mut streams = self.state.backpressure.lock();
+            streams.push(Arc::downgrade(&receiver.backpressure()));
+        }
+
+        let raw_handler = move |payload: &Payload| {
+            if let Some(payload) = decode_payload::<P>(payload) {
+                producer.push(payload);
+            }
+        };
+
+        let subscription = if P::EVENT.is_empty() {
+            self.handle_opcode_raw(P::OPCODE, Box::new(raw_handler))
+        } else {
+            self.handle_event_raw(P::EVENT, Box::new(raw_handler))
+        };
+        receiver.into_stream(subscription)
+    }
+
+    /// Called with the Close frame every time an attached connection is
+    /// closed, so it keeps running across reconnects. Connections which drop
+    /// without a Close frame report `CloseCode::Abnormal`.
+    pub fn handle_close<F>(&self, event: F) -> Subscription
+    where
+        F: Fn(CloseCode, Option<String>) + Send + Sync + 'static,
+    {
+        let id = self.state.next_id();
+        self.state.close_handlers.lock().push((id, Arc::new(event)));
+        Subscription {
+            target: SubscriptionTarget::Close(Arc::downgrade(&self.state)),
+            id,
+        }
+    }
+
+    /// Called whenever a handler panics. Panics are always caught and logged,
+    /// so a buggy handler only loses the packet it was handling. Replaces any
+    /// hook set before.
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where
+        F: Fn(&HandlerPanic) + Send + Sync + 'static,
+    {
+        *self.state.panic_hook.write() = Some(Arc::new(hook));
+    }
+
+    /// The last sequence number the gateway sent us, if any
+    pub fn sequence(&self) -> Option<i32> {
+        match self.state.sequence.load(Ordering::SeqCst) {
+            NO_SEQUENCE => None,
+            seq => Some(seq as i32),
+        }
+    }
+
+    /// The raw counter behind `sequence()`, for tasks that need to read it
+    /// without holding on to the dispatcher. Holds -1 until the first
+    /// sequenced packet arrives.
+    pub fn sequence_counter(&self) -> Arc<AtomicIsize> {
+        self.state.sequence.clone()
+    }
+
+    /// Forget the current sequence, for when a new session is identified
+    /// and the gateway starts counting from 1 again.
+    pub fn reset_sequence(&self) {
+        self.state.sequence.store(NO_SEQUENCE, Ordering::SeqCst);
+    }
+
+    pub fn handle_opcode_raw(&self, opcode: u32, handler: EventHandler) -> Subscription {
+        let list = self.state.opcode_list(opcode);
+        let id = self.state.next_id();
+        self.state.subscribe(&list, id, handler.into())
     }
 
-    pub fn handle_opcode_raw(&self, opcode: u32, handler: EventHandler) {
-        let mut handlers = self.state.opcode_handlers.write();
-        handlers.entry(opcode).or_default().write().push(handler);
+    pub fn handle_event_raw(&self, event: &'static str, handler: EventHandler) -> Subscription {
+        let list = self.state.event_list(event);
+        let id = self.state.next_id();
+        self.state.subscribe(&list, id, handler.into())
     }
 
-    pub fn handle_event_raw(&self, event: &'static str, handler: EventHandler) {
-        let mut handlers = self.state.event_handlers.write();
-        handlers.entry(event).or_default().write().push(handler);
+    pub fn handle_any_event_raw(&self, handler: EventHandler) -> Subscription {
+        let id = self.state.next_id();
+        self.state
+            .subscribe(&self.state.any_event_handlers, id, handler.into())
     }
 }
 
-pub type EventHandler = Box<Fn(&str) + Send + Sync>;
+/// Keeps a handler installed on a `Dispatcher`. Dropping it removes the handler.
+#[must_use = "the handler is removed as soon as its Subscription is dropped"]
+pub struct Subscription {
+    target: SubscriptionTarget,
+    id: usize,
+}
+
+enum SubscriptionTarget {
+    Handlers(Weak<RwLock<Vec<(usize, SharedHandler)>>>),
+    Close(Weak<DispatcherState>),
+    Forgotten,
+}
+
+impl Subscription {
+    /// Leaves the handler installed for as long as the dispatcher lives
+    pub fn forget(mut self) {
+        self.target = SubscriptionTarget::Forgotten;
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let id = self.id;
+        match self.target {
+            SubscriptionTarget::Handlers(ref list) => if let Some(list) = list.upgrade() {
+                list.write().retain(|&(i, _)| i != id);
+            },
+            SubscriptionTarget::Close(ref state) => if let Some(state) = state.upgrade() {
+                state.close_handlers.lock().retain(|&(i, _)| i != id);
+            },
+            SubscriptionTarget::Forgotten => (),
+        }
+    }
+}
+
+pub type EventHandler = Box<Fn(&Payload) + Send + Sync>;
+
+/// A handler which panicked while handling a packet
+#[derive(Clone, Debug)]
+pub struct HandlerPanic {
+    /// The event name, or `Op(n)` for packets without one
+    pub packet: String,
+    /// Position of the handler among the ones called for the packet
+    pub handler: usize,
+    pub message: String,
+}
 
-type HandlerList = Arc<RwLock<Vec<EventHandler>>>;
+/// The status code and reason of a Close frame
+pub type CloseFrame = (CloseCode, Option<String>);
+
+type SharedHandler = Arc<Fn(&Payload) + Send + Sync>;
+type HandlerList = Arc<RwLock<Vec<(usize, SharedHandler)>>>;
 type HandlerMap<K> = RwLock<HashMap<K, HandlerList>>;
 
-#[derive(Default)]
+type CloseHandler = Arc<Fn(CloseCode, Option<String>) + Send + Sync>;
+type PanicHook = Arc<Fn(&HandlerPanic) + Send + Sync>;
+
+/// Value of the sequence counter before any sequenced packet has arrived
+pub const NO_SEQUENCE: isize = -1;
+
 struct DispatcherState {
     opcode_handlers: HandlerMap<u32>,
     event_handlers: HandlerMap<&'static str>,
-    close_handler: Mutex<Option<Box<FnBox(u16, Option<String>) + Send>>>,
+    any_event_handlers: HandlerList,
+    close_handlers: Mutex<Vec<(usize, CloseHandler)>>,
+    backpressure: Mutex<Vec<Weak<Backpressure>>>,
+    panic_hook: RwLock<Option<PanicHook>>,
+    next_handler_id: AtomicUsize,
     last_ping: AtomicUsize,
+    sequence: Arc<AtomicIsize>,
 }
 
-pub fn create(reader: MessageDeflater, writer: websocket::Writer) -> (Dispatcher, Writer) {
-    let state = Arc::new(DispatcherState::default());
-    let handler_state = Arc::downgrade(&state);
-    let dispatcher = Dispatcher { state };
+impl DispatcherState {
+    fn next_id(&self) -> usize {
+        self.next_handler_id.fetch_add(1, Ordering::SeqCst)
+    }
 
-    let (writer, handler_writer) = Writer::create(writer);
-    let handle_messages = handle_messages(handler_state, reader, handler_writer);
-    tokio::spawn(handle_messages.log_errors());
+    fn opcode_list(&self, opcode: u32) -> HandlerList {
+        self.opcode_handlers.write().entry(opcode).or_default().clone()
+    }
+
+    fn event_list(&self, event: &'static str) -> HandlerList {
+        self.event_handlers.write().entry(event).or_default().clone()
+    }
 
-    (dispatcher, writer)
+    fn subscribe(&self, list: &HandlerList, id: usize, handler: SharedHandler) -> Subscription {
+        list.write().push((id, handler));
+        Subscription {
+            target: SubscriptionTarget::Handlers(Arc::downgrade(list)),
+            id,
+        }
+    }
+}
+
+impl Default for DispatcherState {
+    fn default() -> Self {
+        DispatcherState {
+            opcode_handlers: Default::default(),
+            event_handlers: Default::default(),
+            any_event_handlers: Default::default(),
+            close_handlers: Default::default(),
+            backpressure: Default::default(),
+            panic_hook: Default::default(),
+            next_handler_id: AtomicUsize::new(0),
+            last_ping: AtomicUsize::new(0),
+            sequence: Arc::new(AtomicIsize::new(NO_SEQUENCE)),
+        }
+    }
 }
 
+/// Reads the connection until it ends, then runs the close handlers. They get
+/// `CloseCode::Abnormal` if the connection dropped without a Close frame.
 #[async]
 fn handle_messages(
+    state: Weak<DispatcherState>,
+    reader: MessageDeflater,
+    writer: Writer,
+) -> DResult<Option<CloseFrame>> {
+    let result = await!(read_messages(state.clone(), reader, writer));
+    let (status, reason) = match result {
+        Ok(Some((status, ref reason))) => (status, reason.clone()),
+        _ => (CloseCode::Abnormal, None),
+    };
+    run_close_handlers(&state, status, reason);
+    result
+}
+
+#[async]
+fn read_messages(
     state: Weak<DispatcherState>,
     reader: MessageDeflater,
     mut writer: Writer,
-) -> DResult<()> {
+) -> DResult<Option<CloseFrame>> {
+    let mut closed = None;
+
     #[async]
     for message in reader {
         trace!("Decoding packet");
-        if let GatewayMessage::Packet(payload) = message {
-            let frame: Packet<IgnoreData> = json::from_str(&payload)?;
-            let handlers = state.upgrade().map(|s| {
-                if let Some(ref event) = frame.event {
-                    debug!("{} packet received", event);
-                    s.event_handlers.read().get(event.as_ref()).cloned()
-                } else {
-                    debug!("Op({}) packet received", frame.opcode);
-                    s.opcode_handlers.read().get(&frame.opcode).cloned()
-                }
-            });
-
-            match handlers {
-                Some(Some(handlers)) => for (i, handler) in handlers.read().iter().enumerate() {
-                    trace!("Calling packet handler {}", i);
-                    (*handler)(&payload);
-                },
-                Some(None) => {
-                    trace!("No handlers installed");
-                }
-                None => {
-                    info!("Dispatcher was closed");
-                }
-            }
+        if let GatewayMessage::Packet(text) = message {
+            dispatch(&state, &text)?;
+            await!(WaitForCapacity(state.clone()))?;
         } else if let GatewayMessage::OtherFrame(frame) = message {
             match frame {
                 Message::Text(_) => (),
                 Message::Binary(_) => (),
+                // MessageDeflater puts streamed messages back together
+                Message::Start(_) | Message::Chunk(_) | Message::End => (),
                 Message::Ping(data) => {
                     let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
                     let now = now.as_secs() * 1000 + now.subsec_millis() as u64;
@@ -145,14 +372,506 @@ fn handle_messages(
                     trace!("pong received: {}", String::from_utf8_lossy(&data));
                 }
                 Message::Close { status, reason } => {
-                    let handler = state.upgrade().and_then(|s| s.close_handler.lock().take());
-                    if let Some(handler) = handler {
-                        (handler)(status, reason)
+                    info!(
+                        "Gateway closed the connection: {} {}",
+                        status,
+                        reason.as_ref().map(|r| &r[..]).unwrap_or("")
+                    );
+                    closed = Some((status, reason));
+
+                    // Answer the server's Close unless it was answering ours.
+                    // Nothing may follow it, so stop reading either way.
+                    writer.close_received();
+                    if !writer.close_sent() {
+                        let status = if status.is_sendable() {
+                            status
+                        } else {
+                            CloseCode::Normal
+                        };
+                        let reply = Message::Close {
+                            status,
+                            reason: None,
+                        };
+                        if let Err(e) = await!(writer.send(reply)) {
+                            debug!("Failed to answer the gateway's Close: {}", e);
+                        }
                     }
+                    break;
                 }
             }
         }
     }
 
+    Ok(closed)
+}
+
+/// Calls every close handler with the Close frame which ended a connection.
+/// Handlers stay installed until their Subscription is dropped, and may drop
+/// it while being called.
+fn run_close_handlers(state: &Weak<DispatcherState>, status: CloseCode, reason: Option<String>) {
+    let handlers = state
+        .upgrade()
+        .map(|s| {
+            let handlers = s.close_handlers.lock();
+            handlers.iter().map(|(_, h)| h.clone()).collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    for (i, handler) in handlers.into_iter().enumerate() {
+        let reason = reason.clone();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| handler(status, reason)));
+        if let Err(panic) = result {
+            handler_panicked(state, "Close".to_string(), i, panic);
+        }
+    }
+}
+
+/// Parses a packet once and passes it to every handler interested in it
+fn dispatch(state: &Weak<DispatcherState>, text: &str) -> DResult<()> {
+    let payload = Payload::parse(text)?;
+    let handlers = state.upgrade().map(|s| {
+        if let Some(seq) = payload.sequence() {
+            track_sequence(&s.sequence, seq, payload.event());
+        }
+
+        if let Some(event) = payload.event() {
+            debug!("{} packet received", event);
+            let handlers = s.event_handlers.read().get(event).cloned();
+            (handlers, Some(s.any_event_handlers.clone()))
+        } else {
+            debug!("Op({}) packet received", payload.opcode());
+            (s.opcode_handlers.read().get(&payload.opcode()).cloned(), None)
+        }
+    });
+
+    match handlers {
+        Some((None, None)) => {
+            trace!("No handlers installed");
+        }
+        Some((handlers, any_handlers)) => for handlers in handlers.into_iter().chain(any_handlers) {
+            // Handlers may subscribe or unsubscribe while being called
+            let handlers: Vec<_> = handlers.read().iter().map(|(_, h)| h.clone()).collect();
+            for (i, handler) in handlers.iter().enumerate() {
+                trace!("Calling packet handler {}", i);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| (*handler)(&payload)));
+                if let Err(panic) = result {
+                    handler_panicked(state, packet_name(&payload), i, panic);
+                }
+            }
+        },
+        None => {
+            info!("Dispatcher was closed");
+        }
+    }
+
     Ok(())
 }
+
+fn packet_name(payload: &Payload) -> String {
+    match payload.event() {
+        Some(event) => event.to_string(),
+        None => format!("Op({})", payload.opcode()),
+    }
+}
+
+/// Logs a caught handler panic and passes it on to the panic hook
+fn handler_panicked(
+    state: &Weak<DispatcherState>,
+    packet: String,
+    handler: usize,
+    panic: Box<Any + Send>,
+) {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "<non-string panic>".to_string(),
+        },
+    };
+    error!("Handler {} for {} panicked: {}", handler, packet, message);
+
+    let hook = state.upgrade().and_then(|s| s.panic_hook.read().clone());
+    if let Some(hook) = hook {
+        let panic = HandlerPanic {
+            packet,
+            handler,
+            message,
+        };
+        if panic::catch_unwind(AssertUnwindSafe(|| hook(&panic))).is_err() {
+            error!("Handler panic hook panicked");
+        }
+    }
+}
+
+/// Records `seq` as the latest sequence number, logging any events which were
+/// skipped or arrived out of order. Only the reader task writes the counter.
+fn track_sequence(counter: &AtomicIsize, seq: i32, event: Option<&str>) {
+    let seq = seq as isize;
+    let prev = counter.load(Ordering::SeqCst);
+    let event = event.unwrap_or("<none>");
+
+    if prev == NO_SEQUENCE || seq == prev + 1 {
+        counter.store(seq, Ordering::SeqCst);
+    } else if seq > prev + 1 {
+        warn!(
+            "Sequence jumped from {} to {} at {}, {} events were lost",
+            prev,
+            seq,
+            event,
+            seq - prev - 1
+        );
+        counter.store(seq, Ordering::SeqCst);
+    } else {
+        // Keep the newest number so a resume doesn't replay events twice
+        warn!(
+            "Received {} with sequence {} after {}, ignoring out-of-order sequence",
+            event, seq, prev
+        );
+    }
+}
+
+fn decode_payload<P>(payload: &Payload) -> Option<Arc<P>>
+where
+    P: PacketData + 'static,
+{
+    match payload.decode::<P>() {
+        Ok(data) => Some(data),
+        Err(e) => {
+            error!("Failed to deserialize gateway packet: {}", e);
+            None
+        }
+    }
+}
+
+/// Wraps `handler` so it runs for the first packet it can decode, and then
+/// removes itself from `list`
+fn once_handler<H, P>(handler: H, list: &HandlerList, id: usize) -> impl Fn(&Payload) + Send + Sync
+where
+    H: FnOnce(Arc<P>) + Send + 'static,
+    P: PacketData + 'static,
+{
+    let handler = Mutex::new(Some(handler));
+    let list = Arc::downgrade(list);
+    move |payload: &Payload| {
+        let payload = match decode_payload::<P>(payload) {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        if let Some(handler) = handler.lock().take() {
+            if let Some(list) = list.upgrade() {
+                list.write().retain(|&(i, _)| i != id);
+            }
+            handler(payload);
+        }
+    }
+}
+
+/// Resolves once every stream using `Overflow::Backpressure` has room for
+/// another packet
+struct WaitForCapacity(Weak<DispatcherState>);
+
+impl Future for WaitForCapacity {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        let state = match self.0.upgrade() {
+            Some(state) => state,
+            None => return Ok(Async::Ready(())),
+        };
+
+        let mut streams = state.backpressure.lock();
+        streams.retain(|stream| stream.upgrade().is_some());
+        for stream in streams.iter().filter_map(Weak::upgrade) {
+            if let Async::NotReady = stream.poll_capacity() {
+                trace!("Waiting for an event stream to catch up");
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CloseFrame, Dispatcher, Subscription, WaitForCapacity};
+    use discord::gateway::compression::MessageDeflater;
+    use discord::gateway::packets::{Event, Hello, PacketData, Resumed};
+    use discord::gateway::stream::Overflow;
+    use discord::gateway::websocket::tests::{pair, run};
+    use discord::gateway::websocket::{CloseCode, Message};
+    use error::Error;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::future;
+    use futures::prelude::*;
+    use spin::Mutex;
+
+    const HELLO: &str = r#"{"op":10,"d":{"heartbeat_interval":41250}}"#;
+
+    fn resumed(seq: i32) -> String {
+        format!(r#"{{"op":0,"s":{},"t":"RESUMED","d":{{}}}}"#, seq)
+    }
+
+    /// Hands `packet` to the dispatcher as if it came off the connection
+    fn dispatch(dispatcher: &Dispatcher, packet: &str) {
+        super::dispatch(&Arc::downgrade(&dispatcher.state), packet).unwrap();
+    }
+
+    /// Counts the `P` packets the dispatcher hands out
+    fn count<P>(dispatcher: &Dispatcher) -> (Arc<AtomicUsize>, Subscription)
+    where
+        P: PacketData + 'static,
+    {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        let handler = move |_: Arc<P>| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        };
+        let subscription = if P::EVENT.is_empty() {
+            dispatcher.handle_opcode(handler)
+        } else {
+            dispatcher.handle_event(handler)
+        };
+        (count, subscription)
+    }
+
+    fn calls(count: &AtomicUsize) -> usize {
+        count.load(Ordering::SeqCst)
+    }
+
+    /// Records the frames close handlers are called with
+    fn record_closes(dispatcher: &Dispatcher) -> Arc<Mutex<Vec<CloseFrame>>> {
+        let closes = Arc::new(Mutex::new(vec![]));
+        let recorded = closes.clone();
+        dispatcher
+            .handle_close(move |status, reason| recorded.lock().push((status, reason)))
+            .forget();
+        closes
+    }
+
+    #[test]
+    fn sequence_tracking() {
+        let dispatcher = Dispatcher::new();
+        assert_eq!(dispatcher.sequence(), None);
+
+        // Only dispatch packets carry a sequence
+        dispatch(&dispatcher, HELLO);
+        assert_eq!(dispatcher.sequence(), None);
+        dispatch(&dispatcher, &resumed(1));
+        dispatch(&dispatcher, &resumed(2));
+        assert_eq!(dispatcher.sequence(), Some(2));
+
+        // Lost events are skipped over, and late ones don't move it back
+        dispatch(&dispatcher, &resumed(5));
+        assert_eq!(dispatcher.sequence(), Some(5));
+        dispatch(&dispatcher, &resumed(4));
+        dispatch(&dispatcher, &resumed(5));
+        assert_eq!(dispatcher.sequence(), Some(5));
+
+        // A new session counts from 1 again
+        dispatcher.reset_sequence();
+        assert_eq!(dispatcher.sequence(), None);
+        dispatch(&dispatcher, &resumed(1));
+        assert_eq!(dispatcher.sequence(), Some(1));
+    }
+
+    #[test]
+    fn subscriptions() {
+        let dispatcher = Dispatcher::new();
+        let (hellos, hello_subscription) = count::<Hello>(&dispatcher);
+        let (resumes, resumed_subscription) = count::<Resumed>(&dispatcher);
+
+        dispatch(&dispatcher, HELLO);
+        dispatch(&dispatcher, &resumed(1));
+        assert_eq!((calls(&hellos), calls(&resumes)), (1, 1));
+
+        // Dropping the subscription removes the handler, forgetting it doesn't
+        drop(hello_subscription);
+        resumed_subscription.forget();
+        dispatch(&dispatcher, HELLO);
+        dispatch(&dispatcher, &resumed(2));
+        assert_eq!((calls(&hellos), calls(&resumes)), (1, 2));
+    }
+
+    #[test]
+    fn once_handlers() {
+        let dispatcher = Dispatcher::new();
+        let hellos = Arc::new(Mutex::new(vec![]));
+        let received = hellos.clone();
+        let _subscription = dispatcher.handle_opcode_once(move |hello: Arc<Hello>| {
+            received.lock().push(hello.heartbeat_interval);
+        });
+
+        dispatch(&dispatcher, HELLO);
+        dispatch(&dispatcher, HELLO);
+        assert_eq!(*hellos.lock(), vec![41250]);
+    }
+
+    #[test]
+    fn unsubscribe_while_dispatching() {
+        let dispatcher = Dispatcher::new();
+        let (hellos, _subscription) = count::<Hello>(&dispatcher);
+
+        // A handler which removes itself, and installs another in its place
+        let slot = Arc::new(Mutex::new(None));
+        let replaced = slot.clone();
+        let installer = dispatcher.clone();
+        let subscription = dispatcher.handle_opcode(move |_: Arc<Hello>| {
+            let (_, replacement) = count::<Hello>(&installer);
+            let old = replaced.lock().take();
+            drop(old);
+            *replaced.lock() = Some(replacement);
+        });
+        *slot.lock() = Some(subscription);
+
+        // Both changes take effect from the next packet on
+        dispatch(&dispatcher, HELLO);
+        dispatch(&dispatcher, HELLO);
+        assert_eq!(calls(&hellos), 2);
+        assert_eq!(dispatcher.state.opcode_list(Hello::OPCODE).read().len(), 2);
+
+        // Subscriptions outliving the dispatcher have nothing to remove
+        drop(dispatcher);
+        drop(slot.lock().take());
+    }
+
+    #[test]
+    fn any_event_handlers() {
+        let dispatcher = Dispatcher::new();
+        let events = Arc::new(Mutex::new(vec![]));
+        let received = events.clone();
+        let _subscription = dispatcher.handle_any_event(move |event: Arc<Event>| {
+            received.lock().push(event.name().to_string());
+        });
+
+        dispatch(&dispatcher, HELLO);
+        dispatch(&dispatcher, &resumed(1));
+        dispatch(&dispatcher, r#"{"op":0,"s":2,"t":"NEW_EVENT","d":{"a":1}}"#);
+        assert_eq!(*events.lock(), vec!["RESUMED", "NEW_EVENT"]);
+    }
+
+    #[test]
+    fn event_streams() {
+        let dispatcher = Dispatcher::new();
+        let resumes = dispatcher.events::<Resumed>();
+        let hellos = dispatcher.events::<Hello>();
+
+        dispatch(&dispatcher, HELLO);
+        dispatch(&dispatcher, &resumed(1));
+        dispatch(&dispatcher, &resumed(2));
+
+        // Dropping a stream removes its handler
+        drop(hellos);
+        let handlers = dispatcher.state.opcode_list(Hello::OPCODE);
+        assert!(handlers.read().is_empty());
+
+        // Streams end with the dispatcher
+        drop(dispatcher);
+        assert_eq!(resumes.collect().wait().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn backpressure_stalls_the_reader() {
+        let dispatcher = Dispatcher::new();
+        let mut resumes = dispatcher.events_with::<Resumed>(1, Overflow::Backpressure);
+        let state = Arc::downgrade(&dispatcher.state);
+        let has_capacity = || {
+            let wait = future::lazy(|| WaitForCapacity(state.clone()).poll());
+            wait.wait().unwrap().is_ready()
+        };
+        assert!(has_capacity());
+
+        dispatch(&dispatcher, &resumed(1));
+        assert!(!has_capacity());
+        let resume = future::poll_fn(|| resumes.poll()).wait().unwrap();
+        assert!(resume.is_some());
+        assert!(has_capacity());
+
+        // A dropped stream doesn't hold the reader up
+        dispatch(&dispatcher, &resumed(2));
+        assert!(!has_capacity());
+        drop(resumes);
+        assert!(has_capacity());
+    }
+
+    #[test]
+    fn panicking_handlers() {
+        let dispatcher = Dispatcher::new();
+        let panics = Arc::new(Mutex::new(vec![]));
+        let caught = panics.clone();
+        dispatcher.set_panic_hook(move |panic| caught.lock().push(panic.clone()));
+
+        let _panicking = dispatcher.handle_event(|_: Arc<Resumed>| panic!("handler bug"));
+        let (resumes, _subscription) = count::<Resumed>(&dispatcher);
+        let _closing = dispatcher.handle_close(|_, _| panic!("close bug"));
+
+        // Only the packet the handler panicked on is lost to it, and the
+        // handlers after it still run
+        dispatch(&dispatcher, &resumed(1));
+        dispatch(&dispatcher, &resumed(2));
+        assert_eq!(calls(&resumes), 2);
+        assert_eq!(dispatcher.sequence(), Some(2));
+
+        super::run_close_handlers(&Arc::downgrade(&dispatcher.state), CloseCode::Normal, None);
+
+        let panics = panics.lock();
+        let panics: Vec<_> = panics
+            .iter()
+            .map(|p| (&p.packet[..], p.handler, &p.message[..]))
+            .collect();
+        assert_eq!(
+            panics,
+            vec![
+                ("RESUMED", 0, "handler bug"),
+                ("RESUMED", 0, "handler bug"),
+                ("Close", 0, "close bug"),
+            ]
+        );
+    }
+
+    #[test]
+    fn close_handlers_run_when_the_connection_drops() {
+        let dispatcher = Dispatcher::new();
+        let closes = record_closes(&dispatcher);
+
+        let attached = dispatcher.clone();
+        let closed = run(pair().and_then(move |(client, server)| {
+            drop(server);
+            let reader = MessageDeflater::new(client.reader);
+            let (_writer, messages) = attached.attach(reader, client.writer);
+            messages
+        })).unwrap();
+
+        assert_eq!(closed, None);
+        assert_eq!(*closes.lock(), vec![(CloseCode::Abnormal, None)]);
+    }
+
+    #[test]
+    fn close_handlers_get_the_close_frame() {
+        let dispatcher = Dispatcher::new();
+        let closes = record_closes(&dispatcher);
+
+        let attached = dispatcher.clone();
+        let closed = run(pair().and_then(move |(client, server)| {
+            let reader = MessageDeflater::new(client.reader);
+            let (_writer, messages) = attached.attach(reader, client.writer);
+            let close = Message::Close {
+                status: CloseCode::Normal,
+                reason: Some("bye".into()),
+            };
+            server
+                .writer
+                .send(close)
+                .map_err(Error::from)
+                .and_then(move |_| messages)
+        })).unwrap();
+
+        let expected = (CloseCode::Normal, Some("bye".to_string()));
+        assert_eq!(closed, Some(expected.clone()));
+        assert_eq!(*closes.lock(), vec![expected]);
+    }
+}
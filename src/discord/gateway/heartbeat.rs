@@ -1,9 +1,179 @@
-use discord::gateway::Dispatcher;
+use discord::gateway::dispatcher::{Subscription, NO_SEQUENCE};
+use discord::gateway::packets::{Heartbeat as HeartbeatPacket, HeartbeatAck, Hello, Packet};
+use discord::gateway::websocket::{CloseCode, Message};
+use discord::gateway::{Dispatcher, Sender};
 use error::DResult;
+use logging::FutureLogExt;
+
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::prelude::*;
+use futures::sync::oneshot;
+use rand::{thread_rng, Rng};
+use spin::Mutex;
+use tokio::timer::Interval;
+
+/// Handle to the heartbeat of a gateway client. A new heartbeat loop is
+/// started every time a connection says Hello, until every clone of the
+/// handle has been dropped.
+#[derive(Clone)]
+pub struct Heartbeat {
+    state: Arc<HeartbeatState>,
+    _subscriptions: Arc<Vec<Subscription>>,
+}
+
+struct HeartbeatState {
+    sender: Sender,
+    sequence: Arc<AtomicIsize>,
+    generation: AtomicUsize,
+    acked: AtomicBool,
+    zombie: AtomicBool,
+    zombie_signal: Mutex<Option<oneshot::Sender<()>>>,
+    last_sent: Mutex<Option<Instant>>,
+    latency: Mutex<Option<Duration>>,
+    interval: Mutex<Option<Duration>>,
+}
+
+impl Heartbeat {
+    /// Round trip time between our last heartbeat and the gateway's ACK
+    pub fn latency(&self) -> Option<Duration> {
+        *self.state.latency.lock()
+    }
+
+    /// The interval requested by the gateway in its Hello packet
+    pub fn interval(&self) -> Option<Duration> {
+        *self.state.interval.lock()
+    }
+
+    /// Whether the gateway stopped acknowledging our heartbeats. A zombied
+    /// connection has been closed and needs a reconnect.
+    pub fn is_zombie(&self) -> bool {
+        self.state.zombie.load(Ordering::SeqCst)
+    }
+
+    /// Resolves when the current connection misses a heartbeat ACK. Only the
+    /// most recently requested signal fires.
+    pub fn zombie_signal(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        *self.state.zombie_signal.lock() = Some(tx);
+        rx
+    }
+
+    /// Stops the heartbeat loop of the current connection
+    pub fn stop(&self) {
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl HeartbeatState {
+    fn send(&self) -> DResult<()> {
+        let sequence = match self.sequence.load(Ordering::SeqCst) {
+            NO_SEQUENCE => None,
+            seq => Some(seq as i32),
+        };
+        let heartbeat = Packet::new(HeartbeatPacket(sequence));
+        trace!("Sending heartbeat: {:?}", heartbeat);
+        *self.last_sent.lock() = Some(Instant::now());
+        self.sender.send_packet(&heartbeat)
+    }
+
+    fn ack(&self) {
+        self.acked.store(true, Ordering::SeqCst);
+        if let Some(sent) = *self.last_sent.lock() {
+            let latency = sent.elapsed();
+            trace!("Heartbeat acknowledged after {:?}", latency);
+            *self.latency.lock() = Some(latency);
+        }
+    }
+
+    fn zombied(&self) {
+        warn!("Gateway did not acknowledge the last heartbeat, closing connection");
+        self.zombie.store(true, Ordering::SeqCst);
+
+        // Queued before the signal, since whoever waits on it may detach the
+        // sender. Anything but a normal close keeps the session resumable.
+        let close = Message::Close {
+            status: CloseCode::UnknownError,
+            reason: Some("heartbeat timed out".into()),
+        };
+        if let Err(e) = self.sender.send(close) {
+            warn!("Failed to close the zombied connection: {}", e);
+        }
+
+        if let Some(signal) = self.zombie_signal.lock().take() {
+            signal.send(()).ok();
+        }
+    }
+}
+
+/// Installs the heartbeat handlers on `dispatcher`. Every Hello starts a new
+/// heartbeat loop which runs until `stop` is called, the next Hello arrives,
+/// or the gateway misses an ACK.
+pub fn start(dispatcher: &Dispatcher, sender: Sender) -> Heartbeat {
+    let state = Arc::new(HeartbeatState {
+        sender,
+        sequence: dispatcher.sequence_counter(),
+        generation: AtomicUsize::new(0),
+        acked: AtomicBool::new(true),
+        zombie: AtomicBool::new(false),
+        zombie_signal: Mutex::new(None),
+        last_sent: Mutex::new(None),
+        latency: Mutex::new(None),
+        interval: Mutex::new(None),
+    });
+
+    let mut subscriptions = Vec::with_capacity(3);
+
+    let hello_state = state.clone();
+    subscriptions.push(dispatcher.handle_opcode(move |packet: Arc<Hello>| {
+        let interval = Duration::from_millis(packet.heartbeat_interval);
+        let generation = hello_state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        hello_state.acked.store(true, Ordering::SeqCst);
+        hello_state.zombie.store(false, Ordering::SeqCst);
+        *hello_state.interval.lock() = Some(interval);
+
+        let task = run_heartbeat(hello_state.clone(), generation, interval);
+        tokio::spawn(task.log_errors());
+    }));
+
+    let request_state = state.clone();
+    subscriptions.push(dispatcher.handle_opcode(move |_: Arc<HeartbeatPacket>| {
+        debug!("Gateway requested a heartbeat");
+        if let Err(e) = request_state.send() {
+            error!("Failed to answer heartbeat request: {}", e);
+        }
+    }));
+
+    let ack_state = state.clone();
+    subscriptions.push(dispatcher.handle_opcode(move |_: Arc<HeartbeatAck>| ack_state.ack()));
+
+    Heartbeat {
+        state,
+        _subscriptions: Arc::new(subscriptions),
+    }
+}
 
 #[async]
-pub fn start_heartbeat(_dispatcher: Dispatcher) -> DResult<()> {
-    unimplemented!()
+fn run_heartbeat(state: Arc<HeartbeatState>, generation: usize, interval: Duration) -> DResult<()> {
+    // Spread out the first heartbeat so reconnecting clients don't all beat at once
+    let millis = interval.as_secs() * 1000 + interval.subsec_millis() as u64;
+    let jitter = Duration::from_millis(thread_rng().gen_range(0, millis.max(1)));
+
+    #[async]
+    for _instant in Interval::new(Instant::now() + jitter, interval) {
+        if state.generation.load(Ordering::SeqCst) != generation {
+            break;
+        }
+
+        if !state.acked.swap(false, Ordering::SeqCst) {
+            state.zombied();
+            break;
+        }
+
+        state.send()?;
+    }
+
+    Ok(())
 }
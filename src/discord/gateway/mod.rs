@@ -1,15 +1,38 @@
+use discord::gateway::packets::{Packet, PacketData};
+use discord::gateway::websocket::CloseCode;
 use error::{DResult, Error};
+use logging::FutureLogExt;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use flate2::Decompress;
 use futures::prelude::*;
-use futures::sync::BiLock;
+use futures::sync::{mpsc, BiLock};
+use futures::task::AtomicTask;
+use serde_json as json;
+use spin::Mutex;
+use tokio::timer::Deadline;
 
 pub use discord::gateway::dispatcher::Dispatcher;
+pub use discord::gateway::supervisor::supervise;
+
+/// The largest payload the gateway accepts. Anything bigger gets the
+/// connection closed, so it is refused before it is sent.
+pub const MAX_PAYLOAD_SIZE: usize = 4096;
+
+/// How long `Writer::close` waits for the server's Close frame before
+/// shutting down the connection anyway
+pub const CLOSE_TIMEOUT_MS: u64 = 5000;
 
 pub mod compression;
 pub mod dispatcher;
 pub mod heartbeat;
 pub mod packets;
+pub mod payload;
+pub mod session;
+pub mod stream;
+pub mod supervisor;
 pub mod websocket;
 
 #[derive(Debug)]
@@ -20,11 +43,28 @@ pub enum GatewayMessage {
 
 pub struct Writer {
     writer: BiLock<websocket::Writer>,
+    closing: Arc<Closing>,
+}
+
+/// Progress of the closing handshake, shared by both halves of a `Writer`
+struct Closing {
+    sent: AtomicBool,
+    received: AtomicBool,
+    task: AtomicTask,
+}
+
+/// A cloneable handle for queueing messages on a `Writer` from synchronous code,
+/// such as packet handlers. The writer behind it can be swapped out when the
+/// client reconnects.
+#[derive(Clone)]
+pub struct Sender {
+    tx: Arc<Mutex<Option<mpsc::UnboundedSender<websocket::Message>>>>,
 }
 
 pub struct Client {
     pub dispatcher: dispatcher::Dispatcher,
-    pub writer: Writer,
+    pub sender: Sender,
+    pub heartbeat: heartbeat::Heartbeat,
 }
 
 struct PartialClient {
@@ -32,25 +72,50 @@ struct PartialClient {
     pub writer: websocket::Writer,
 }
 
+/// Opens a single gateway connection. Use `supervise` for a client which
+/// reconnects on its own.
 pub fn connect(gateway: &str) -> impl Future<Item = Client, Error = Error> {
-    let uri = format!("{}/?v=6&encoding=json&compress=zlib-stream", gateway);
+    let uri = gateway_uri(gateway);
     async_block! {
         let PartialClient { reader, writer } = await!(new_connection(uri))?;
-        let (dispatcher, writer) = dispatcher::create(reader, writer);
 
-        Ok(Client { dispatcher, writer })
+        // Everything is installed before the reader starts, so the Hello
+        // can't arrive ahead of its handler
+        let dispatcher = Dispatcher::new();
+        let sender = Sender::detached();
+        let heartbeat = heartbeat::start(&dispatcher, sender.clone());
+
+        let stop_heartbeat = heartbeat.clone();
+        dispatcher
+            .handle_close(move |_, _| stop_heartbeat.stop())
+            .forget();
+
+        let (writer, connection) = dispatcher.attach(reader, writer);
+        sender.attach(writer);
+        tokio::spawn(connection.map(|_| ()).log_errors());
+
+        Ok(Client {
+            dispatcher,
+            sender,
+            heartbeat,
+        })
     }
 }
 
+fn gateway_uri(gateway: &str) -> String {
+    format!("{}/?v=6&encoding=json&compress=zlib-stream", gateway)
+}
+
 #[async]
 fn new_connection(uri: String) -> DResult<PartialClient> {
     let uri = uri.parse().unwrap();
-    let ws_client = await!(websocket::connect(uri))?;
-
-    let deflater = compression::MessageDeflater {
-        reader: ws_client.reader,
-        zlib: Decompress::new(true),
+    // READY can run to megabytes, so it is inflated as it arrives
+    let settings = websocket::ConnectSettings {
+        stream_messages: true,
+        ..Default::default()
     };
+    let ws_client = await!(websocket::connect_with_settings(uri, settings))?;
+    let deflater = compression::MessageDeflater::new(ws_client.reader);
 
     Ok(PartialClient {
         reader: deflater,
@@ -61,13 +126,47 @@ fn new_connection(uri: String) -> DResult<PartialClient> {
 impl Writer {
     fn create(writer: websocket::Writer) -> (Writer, Writer) {
         let (left, right) = BiLock::new(writer);
-        (Writer { writer: left }, Writer { writer: right })
+        let closing = Arc::new(Closing {
+            sent: AtomicBool::new(false),
+            received: AtomicBool::new(false),
+            task: AtomicTask::new(),
+        });
+
+        let left = Writer {
+            writer: left,
+            closing: closing.clone(),
+        };
+        (left, Writer { writer: right, closing })
+    }
+
+    /// Whether a Close frame has been sent on this connection
+    pub fn close_sent(&self) -> bool {
+        self.closing.sent.load(Ordering::SeqCst)
+    }
+
+    /// Records that the server sent its Close frame
+    fn close_received(&self) {
+        self.closing.received.store(true, Ordering::SeqCst);
+        self.closing.task.notify();
     }
 }
 
 impl Writer {
     #[async]
     pub fn send(self, mut message: websocket::Message) -> DResult<Writer> {
+        check_payload_size(&message)?;
+
+        // Nothing may follow a Close frame, including a second one
+        let is_close = match message {
+            websocket::Message::Close { .. } => true,
+            _ => false,
+        };
+        if self.close_sent() || (is_close && self.closing.sent.swap(true, Ordering::SeqCst)) {
+            debug!("Connection is closing, dropping {:?}", message);
+            return Ok(self);
+        }
+
+        let closing = self.closing.clone();
         let mut writer = await!(self.writer.lock()).map_err(|_| Error::FutureError)?;
 
         loop {
@@ -88,6 +187,129 @@ impl Writer {
         }
 
         let writer = writer.unlock();
-        Ok(Writer { writer })
+        Ok(Writer { writer, closing })
+    }
+
+    /// Runs the closing handshake. Sends a Close frame, waits up to
+    /// `CLOSE_TIMEOUT_MS` for the server to answer it, and then shuts down the
+    /// underlying stream.
+    #[async]
+    pub fn close(self, status: CloseCode, reason: Option<String>) -> DResult<()> {
+        let closing = self.closing.clone();
+        let writer = await!(self.send(websocket::Message::Close { status, reason }))?;
+
+        let timeout = Duration::from_millis(CLOSE_TIMEOUT_MS);
+        let deadline = Instant::now() + timeout;
+        match await!(Deadline::new(CloseReceived(closing), deadline)) {
+            Ok(()) => trace!("Closing handshake finished"),
+            Err(ref e) if e.is_elapsed() => {
+                warn!("Server didn't answer our Close frame within {:?}", timeout)
+            }
+            Err(e) => return Err(e.into_timer().map(Error::from).unwrap_or(Error::FutureError)),
+        }
+
+        let mut writer = await!(writer.writer.lock()).map_err(|_| Error::FutureError)?;
+        loop {
+            match writer.close()? {
+                Async::Ready(()) => break,
+                Async::NotReady => yield Async::NotReady,
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns a task which owns this writer and sends everything queued on the
+    /// returned `Sender`. The task exits once every `Sender` has been dropped.
+    pub fn into_sender(self) -> Sender {
+        let sender = Sender::detached();
+        sender.attach(self);
+        sender
+    }
+}
+
+#[async]
+fn forward_messages(
+    mut writer: Writer,
+    rx: mpsc::UnboundedReceiver<websocket::Message>,
+) -> DResult<()> {
+    #[async]
+    for message in rx.map_err(|_| Error::FutureError) {
+        if let websocket::Message::Close { status, reason } = message {
+            return await!(writer.close(status, reason));
+        }
+        writer = await!(writer.send(message))?;
+    }
+    Ok(())
+}
+
+/// Resolves once the server's Close frame has been read
+struct CloseReceived(Arc<Closing>);
+
+impl Future for CloseReceived {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.0.task.register();
+        if self.0.received.load(Ordering::SeqCst) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Sender {
+    /// A sender without a connection. Sending fails with `Error::NotConnected`
+    /// until a writer is attached.
+    pub fn detached() -> Sender {
+        Sender {
+            tx: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Routes all further messages to `writer`, replacing the previous one
+    pub fn attach(&self, writer: Writer) {
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(forward_messages(writer, rx).log_errors());
+        *self.tx.lock() = Some(tx);
+    }
+
+    /// Lets go of the current writer once everything already queued for it
+    /// has been sent.
+    pub fn detach(&self) {
+        *self.tx.lock() = None;
+    }
+
+    /// Fails with `Error::PayloadTooLarge` if the message is over
+    /// `MAX_PAYLOAD_SIZE`, instead of letting the gateway drop the connection
+    pub fn send(&self, message: websocket::Message) -> DResult<()> {
+        check_payload_size(&message)?;
+        match *self.tx.lock() {
+            Some(ref tx) => tx.unbounded_send(message).map_err(|_| Error::NotConnected),
+            None => Err(Error::NotConnected),
+        }
+    }
+
+    pub fn send_packet<P>(&self, packet: &Packet<P>) -> DResult<()>
+    where
+        P: PacketData,
+    {
+        let payload = json::to_string(packet)?;
+        trace!("Sending packet: {}", payload);
+        self.send(websocket::Message::Text(payload))
+    }
+}
+
+fn check_payload_size(message: &websocket::Message) -> DResult<()> {
+    let size = match message {
+        websocket::Message::Text(text) => text.len(),
+        websocket::Message::Binary(data) => data.len(),
+        _ => 0,
+    };
+
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(Error::PayloadTooLarge(size));
     }
+    Ok(())
 }
@@ -0,0 +1,370 @@
+use discord::gateway::packets::PacketData;
+use discord::gateway::payload::Payload;
+use discord::models::channel::Channel;
+use discord::models::guild::{Emoji, Guild, Member, Role, UnavailableGuild};
+use discord::models::message::{Message, PartialEmoji};
+use discord::models::snowflake::Snowflake;
+use discord::models::status::Presence;
+use discord::models::user::User;
+use discord::models::voice::VoiceState;
+
+use serde_json as json;
+
+dispatch_events! {
+    Ready(Ready),
+    Resumed(Resumed),
+    ChannelCreate(ChannelCreate),
+    ChannelUpdate(ChannelUpdate),
+    ChannelDelete(ChannelDelete),
+    ChannelPinsUpdate(ChannelPinsUpdate),
+    GuildCreate(GuildCreate),
+    GuildUpdate(GuildUpdate),
+    GuildDelete(GuildDelete),
+    GuildBanAdd(GuildBanAdd),
+    GuildBanRemove(GuildBanRemove),
+    GuildEmojisUpdate(GuildEmojisUpdate),
+    GuildIntegrationsUpdate(GuildIntegrationsUpdate),
+    GuildMemberAdd(GuildMemberAdd),
+    GuildMemberRemove(GuildMemberRemove),
+    GuildMemberUpdate(GuildMemberUpdate),
+    GuildMembersChunk(GuildMembersChunk),
+    GuildRoleCreate(GuildRoleCreate),
+    GuildRoleUpdate(GuildRoleUpdate),
+    GuildRoleDelete(GuildRoleDelete),
+    MessageCreate(MessageCreate),
+    MessageUpdate(MessageUpdate),
+    MessageDelete(MessageDelete),
+    MessageDeleteBulk(MessageDeleteBulk),
+    MessageReactionAdd(MessageReactionAdd),
+    MessageReactionRemove(MessageReactionRemove),
+    MessageReactionRemoveAll(MessageReactionRemoveAll),
+    PresenceUpdate(PresenceUpdate),
+    TypingStart(TypingStart),
+    UserUpdate(UserUpdate),
+    VoiceStateUpdate(VoiceStateUpdate),
+    VoiceServerUpdate(VoiceServerUpdate),
+    WebhooksUpdate(WebhooksUpdate),
+}
+
+impl Event {
+    /// Decodes a whole dispatch packet, using its `t` field to pick the event
+    pub fn from_packet(packet: &str) -> json::Result<Event> {
+        let payload = Payload::parse(packet)?;
+        Event::decode(payload.event().unwrap_or(""), payload.raw_data())
+    }
+}
+
+//--------------------
+// Connection
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ready {
+    pub v: i32,
+    pub user: User,
+    #[serde(default)]
+    pub private_channels: Vec<Channel>,
+    #[serde(default)]
+    pub guilds: Vec<UnavailableGuild>,
+    pub session_id: String,
+    #[serde(rename = "_trace", default)]
+    pub trace: Vec<String>,
+}
+packet_payload!(Ready, event: READY);
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Resumed {
+    #[serde(rename = "_trace", default)]
+    pub trace: Vec<String>,
+}
+packet_payload!(Resumed, event: RESUMED);
+
+//--------------------
+// Channels
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelCreate(pub Channel);
+packet_payload!(ChannelCreate, event: CHANNEL_CREATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelUpdate(pub Channel);
+packet_payload!(ChannelUpdate, event: CHANNEL_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelDelete(pub Channel);
+packet_payload!(ChannelDelete, event: CHANNEL_DELETE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelPinsUpdate {
+    pub channel_id: Snowflake,
+    pub last_pin_timestamp: Option<String>,
+}
+packet_payload!(ChannelPinsUpdate, event: CHANNEL_PINS_UPDATE);
+
+//--------------------
+// Guilds
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildCreate(pub Guild);
+packet_payload!(GuildCreate, event: GUILD_CREATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildUpdate(pub Guild);
+packet_payload!(GuildUpdate, event: GUILD_UPDATE);
+
+/// `unavailable` is set when the guild went into an outage rather than
+/// the user leaving it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildDelete(pub UnavailableGuild);
+packet_payload!(GuildDelete, event: GUILD_DELETE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildBanAdd {
+    pub guild_id: Snowflake,
+    pub user: User,
+}
+packet_payload!(GuildBanAdd, event: GUILD_BAN_ADD);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildBanRemove {
+    pub guild_id: Snowflake,
+    pub user: User,
+}
+packet_payload!(GuildBanRemove, event: GUILD_BAN_REMOVE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildEmojisUpdate {
+    pub guild_id: Snowflake,
+    pub emojis: Vec<Emoji>,
+}
+packet_payload!(GuildEmojisUpdate, event: GUILD_EMOJIS_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildIntegrationsUpdate {
+    pub guild_id: Snowflake,
+}
+packet_payload!(GuildIntegrationsUpdate, event: GUILD_INTEGRATIONS_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildMemberAdd {
+    pub guild_id: Snowflake,
+    #[serde(flatten)]
+    pub member: Member,
+}
+packet_payload!(GuildMemberAdd, event: GUILD_MEMBER_ADD);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildMemberRemove {
+    pub guild_id: Snowflake,
+    pub user: User,
+}
+packet_payload!(GuildMemberRemove, event: GUILD_MEMBER_REMOVE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildMemberUpdate {
+    pub guild_id: Snowflake,
+    pub roles: Vec<Snowflake>,
+    pub user: User,
+    pub nick: Option<String>,
+}
+packet_payload!(GuildMemberUpdate, event: GUILD_MEMBER_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildMembersChunk {
+    pub guild_id: Snowflake,
+    pub members: Vec<Member>,
+}
+packet_payload!(GuildMembersChunk, event: GUILD_MEMBERS_CHUNK);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildRoleCreate {
+    pub guild_id: Snowflake,
+    pub role: Role,
+}
+packet_payload!(GuildRoleCreate, event: GUILD_ROLE_CREATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildRoleUpdate {
+    pub guild_id: Snowflake,
+    pub role: Role,
+}
+packet_payload!(GuildRoleUpdate, event: GUILD_ROLE_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildRoleDelete {
+    pub guild_id: Snowflake,
+    pub role_id: Snowflake,
+}
+packet_payload!(GuildRoleDelete, event: GUILD_ROLE_DELETE);
+
+//--------------------
+// Messages
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageCreate(pub Message);
+packet_payload!(MessageCreate, event: MESSAGE_CREATE);
+
+/// Edits only carry the fields which changed, and embed updates can arrive
+/// without an author or content at all
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageUpdate {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub author: Option<User>,
+    pub content: Option<String>,
+    pub edited_timestamp: Option<String>,
+    pub mentions: Option<Vec<User>>,
+    pub mention_roles: Option<Vec<Snowflake>>,
+    pub embeds: Option<Vec<json::Value>>,
+    pub pinned: Option<bool>,
+}
+packet_payload!(MessageUpdate, event: MESSAGE_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageDelete {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+}
+packet_payload!(MessageDelete, event: MESSAGE_DELETE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageDeleteBulk {
+    pub ids: Vec<Snowflake>,
+    pub channel_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+}
+packet_payload!(MessageDeleteBulk, event: MESSAGE_DELETE_BULK);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageReactionAdd {
+    pub user_id: Snowflake,
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub emoji: PartialEmoji,
+}
+packet_payload!(MessageReactionAdd, event: MESSAGE_REACTION_ADD);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageReactionRemove {
+    pub user_id: Snowflake,
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub emoji: PartialEmoji,
+}
+packet_payload!(MessageReactionRemove, event: MESSAGE_REACTION_REMOVE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageReactionRemoveAll {
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+}
+packet_payload!(MessageReactionRemoveAll, event: MESSAGE_REACTION_REMOVE_ALL);
+
+//--------------------
+// Users
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PresenceUpdate(pub Presence);
+packet_payload!(PresenceUpdate, event: PRESENCE_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TypingStart {
+    pub channel_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub user_id: Snowflake,
+    /// Unix time in seconds
+    pub timestamp: i64,
+}
+packet_payload!(TypingStart, event: TYPING_START);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserUpdate(pub User);
+packet_payload!(UserUpdate, event: USER_UPDATE);
+
+//--------------------
+// Voice
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VoiceStateUpdate(pub VoiceState);
+packet_payload!(VoiceStateUpdate, event: VOICE_STATE_UPDATE);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VoiceServerUpdate {
+    pub token: String,
+    pub guild_id: Snowflake,
+    pub endpoint: String,
+}
+packet_payload!(VoiceServerUpdate, event: VOICE_SERVER_UPDATE);
+
+//--------------------
+// Webhooks
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebhooksUpdate {
+    pub guild_id: Snowflake,
+    pub channel_id: Snowflake,
+}
+packet_payload!(WebhooksUpdate, event: WEBHOOKS_UPDATE);
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Resumed, TypingStart};
+    use discord::models::snowflake::Snowflake;
+
+    use serde_json as json;
+
+    #[test]
+    fn known_events() {
+        let packet = r#"{"op":0,"s":3,"t":"TYPING_START","d":{
+            "channel_id":"41771983423143937",
+            "user_id":"80351110224678912",
+            "timestamp":1537138000
+        }}"#;
+        let event = Event::from_packet(packet).unwrap();
+        assert_eq!(event.name(), "TYPING_START");
+        match event {
+            Event::TypingStart(TypingStart {
+                channel_id,
+                guild_id,
+                user_id,
+                timestamp,
+            }) => {
+                assert_eq!(channel_id, Snowflake(41771983423143937));
+                assert_eq!(guild_id, None);
+                assert_eq!(user_id, Snowflake(80351110224678912));
+                assert_eq!(timestamp, 1537138000);
+            }
+            other => panic!("Expected TYPING_START, got {:?}", other),
+        }
+
+        match Event::decode("RESUMED", r#"{"_trace":["gateway-prd-1"]}"#).unwrap() {
+            Event::Resumed(Resumed { ref trace }) => assert_eq!(trace, &["gateway-prd-1"]),
+            other => panic!("Expected RESUMED, got {:?}", other),
+        }
+
+        // A known event with the wrong shape is an error, not an unknown event
+        assert!(Event::decode("TYPING_START", r#"{"channel_id":5}"#).is_err());
+    }
+
+    #[test]
+    fn unknown_events() {
+        let data = r#"{"guild_id":"41771983423143937","status":{"id":1}}"#;
+        match Event::decode("GUILD_JOIN_REQUEST_UPDATE", data).unwrap() {
+            Event::Unknown { ref name, ref raw } => {
+                assert_eq!(name, "GUILD_JOIN_REQUEST_UPDATE");
+                assert_eq!(raw, &json::from_str::<json::Value>(data).unwrap());
+            }
+            other => panic!("Expected an unknown event, got {:?}", other),
+        }
+
+        // Packets without a name decode as an unknown event too
+        let event = Event::from_packet(r#"{"op":0,"d":null}"#).unwrap();
+        assert_eq!(event.name(), "");
+
+        assert!(Event::decode("GUILD_JOIN_REQUEST_UPDATE", "{").is_err());
+    }
+}
@@ -39,3 +39,41 @@ macro_rules! packet_payload {
         }
     };
 }
+
+macro_rules! dispatch_events {
+    ($($variant:ident($payload:ident),)*) => {
+        /// A dispatch packet decoded according to its event name
+        #[derive(Clone, Debug)]
+        pub enum Event {
+            $($variant($payload),)*
+            /// An event this client doesn't know the shape of
+            Unknown {
+                name: String,
+                raw: $crate::serde_json::Value,
+            },
+        }
+
+        impl Event {
+            /// Decodes `data`, the `d` field of a dispatch packet whose `t` is `name`
+            pub fn decode(name: &str, data: &str) -> $crate::serde_json::Result<Event> {
+                $(
+                    if name == <$payload as PacketData>::EVENT {
+                        return $crate::serde_json::from_str(data).map(Event::$variant);
+                    }
+                )*
+
+                Ok(Event::Unknown {
+                    name: name.into(),
+                    raw: $crate::serde_json::from_str(data)?,
+                })
+            }
+
+            pub fn name(&self) -> &str {
+                match self {
+                    $(Event::$variant(_) => <$payload as PacketData>::EVENT,)*
+                    Event::Unknown { name, .. } => &name[..],
+                }
+            }
+        }
+    };
+}
@@ -4,9 +4,13 @@ use std::borrow::Cow;
 
 use serde::{Deserialize, Serialize};
 
+pub use discord::gateway::packets::events::*;
+
 #[macro_use]
 mod macros;
 
+pub mod events;
+
 //--------------------
 // Packet structs
 
@@ -24,7 +28,9 @@ pub struct Identify<'a> {
     pub properties: IdentifyProperties<'a>,
     pub compress: bool,
     pub large_threshold: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub shard: Option<(i32, i32)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub presence: Option<UpdateStatus<'a>>, // TODO: Prescence object
 }
 packet_payload!(Identify<'a>, op: 2);
@@ -37,6 +43,23 @@ pub struct UpdateStatus<'a> {
     pub afk: bool,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Resume<'a> {
+    pub token: Cow<'a, str>,
+    pub session_id: Cow<'a, str>,
+    pub seq: i32,
+}
+packet_payload!(Resume<'a>, op: 6);
+
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct Reconnect;
+packet_payload!(Reconnect, op: 7, skip: true);
+
+/// The inner value is whether the session may be resumed
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InvalidSession(pub bool);
+packet_payload!(InvalidSession, op: 9);
+
 #[derive(Copy, Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Hello {
     pub heartbeat_interval: u64,
@@ -80,7 +103,7 @@ where
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sequence: Option<i32>,
 
-    #[serde(rename = "e")]
+    #[serde(rename = "t")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub event: Option<Cow<'static, str>>,
 }
@@ -122,7 +145,7 @@ pub struct PartialPacket<'a> {
     #[serde(rename = "s")]
     pub sequence: Option<i32>,
 
-    #[serde(rename = "e")]
+    #[serde(rename = "t")]
     #[serde(borrow)]
     pub event: Option<Cow<'a, str>>,
 }
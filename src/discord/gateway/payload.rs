@@ -0,0 +1,163 @@
+use discord::gateway::packets::{Event, PacketData};
+
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use serde_json::{self as json, value::RawValue};
+
+/// A gateway packet whose envelope has been parsed, but whose `d` field is
+/// left as raw json until a handler asks for it. Each payload type is only
+/// decoded once per packet, and shared by every handler which wants it.
+pub struct Payload<'a> {
+    text: &'a str,
+    envelope: Envelope<'a>,
+    decoded: RefCell<Vec<(TypeId, Box<Any>)>>,
+}
+
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(rename = "op")]
+    opcode: u32,
+
+    #[serde(rename = "s")]
+    sequence: Option<i32>,
+
+    #[serde(rename = "t")]
+    #[serde(borrow)]
+    event: Option<Cow<'a, str>>,
+
+    #[serde(rename = "d")]
+    #[serde(borrow)]
+    data: Option<&'a RawValue>,
+}
+
+impl<'a> Payload<'a> {
+    pub fn parse(text: &'a str) -> json::Result<Payload<'a>> {
+        Ok(Payload {
+            text,
+            envelope: json::from_str(text)?,
+            decoded: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn opcode(&self) -> u32 {
+        self.envelope.opcode
+    }
+
+    pub fn sequence(&self) -> Option<i32> {
+        self.envelope.sequence
+    }
+
+    pub fn event(&self) -> Option<&str> {
+        self.envelope.event.as_ref().map(|e| e.as_ref())
+    }
+
+    /// The whole packet as it came off the wire
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// The undecoded `d` field
+    pub fn raw_data(&self) -> &'a str {
+        self.envelope.data.map(RawValue::get).unwrap_or("null")
+    }
+
+    /// Decodes the `d` field as `P`, sharing the result of an earlier call
+    pub fn decode<P>(&self) -> json::Result<Arc<P>>
+    where
+        P: PacketData + 'static,
+    {
+        self.cached(|| json::from_str(self.raw_data()))
+    }
+
+    /// Decodes a dispatch packet into an `Event`, sharing the result of an
+    /// earlier call
+    pub fn decode_event(&self) -> json::Result<Arc<Event>> {
+        self.cached(|| Event::decode(self.event().unwrap_or(""), self.raw_data()))
+    }
+
+    fn cached<T, F>(&self, decode: F) -> json::Result<Arc<T>>
+    where
+        T: 'static,
+        F: FnOnce() -> json::Result<T>,
+    {
+        let id = TypeId::of::<T>();
+        {
+            let decoded = self.decoded.borrow();
+            let value = decoded.iter().find(|(t, _)| *t == id);
+            if let Some(value) = value.and_then(|(_, v)| v.downcast_ref::<Arc<T>>()) {
+                return Ok(value.clone());
+            }
+        }
+
+        let value = Arc::new(decode()?);
+        self.decoded.borrow_mut().push((id, Box::new(value.clone())));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Payload;
+    use discord::gateway::packets::{Event, Resumed, TypingStart};
+
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    const RESUMED: &str = r#"{"op":0,"s":7,"t":"RESUMED","d":{"_trace":["gateway-prd-1"]}}"#;
+
+    #[test]
+    fn envelope() {
+        let payload = Payload::parse(RESUMED).unwrap();
+        assert_eq!(payload.opcode(), 0);
+        assert_eq!(payload.sequence(), Some(7));
+        assert_eq!(payload.event(), Some("RESUMED"));
+        assert_eq!(payload.raw_data(), r#"{"_trace":["gateway-prd-1"]}"#);
+        assert_eq!(payload.text(), RESUMED);
+
+        let payload = Payload::parse(r#"{"op":11}"#).unwrap();
+        assert_eq!(payload.sequence(), None);
+        assert_eq!(payload.event(), None);
+        assert_eq!(payload.raw_data(), "null");
+    }
+
+    #[test]
+    fn decoded_once_per_type() {
+        let payload = Payload::parse(RESUMED).unwrap();
+
+        let first = payload.decode::<Resumed>().unwrap();
+        let second = payload.decode::<Resumed>().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.trace, vec!["gateway-prd-1"]);
+
+        // Other types get their own entry
+        let event = payload.decode_event().unwrap();
+        assert!(Arc::ptr_eq(&event, &payload.decode_event().unwrap()));
+        match *event {
+            Event::Resumed(_) => (),
+            ref other => panic!("Expected RESUMED, got {:?}", other),
+        }
+        assert_eq!(payload.decoded.borrow().len(), 2);
+
+        // Failures aren't cached, so every caller sees the error
+        assert!(payload.decode::<TypingStart>().is_err());
+        assert!(payload.decode::<TypingStart>().is_err());
+        assert_eq!(payload.decoded.borrow().len(), 2);
+    }
+
+    #[test]
+    fn decode_runs_once() {
+        let payload = Payload::parse(RESUMED).unwrap();
+        let calls = Cell::new(0);
+        for _ in 0..3 {
+            let value = payload.cached(|| {
+                calls.set(calls.get() + 1);
+                Ok(42u32)
+            });
+            assert_eq!(*value.unwrap(), 42);
+        }
+        assert_eq!(calls.get(), 1);
+    }
+}
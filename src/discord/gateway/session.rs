@@ -0,0 +1,255 @@
+use discord::gateway::dispatcher::{Subscription, NO_SEQUENCE};
+use discord::gateway::packets::{Hello, Identify, IdentifyProperties, InvalidSession, Packet};
+use discord::gateway::packets::{Ready, Reconnect, Resume, Resumed};
+use discord::gateway::websocket::{CloseCode, Message};
+use discord::gateway::{Dispatcher, Sender};
+use discord::models::user::User;
+use error::{DResult, Error};
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::sync::mpsc;
+use rand::{thread_rng, Rng};
+use spin::Mutex;
+use tokio::timer::Delay;
+
+/// Login state for a gateway connection. This outlives any single websocket
+/// so that a dropped connection can be resumed instead of re-identified.
+#[derive(Clone)]
+pub struct Session {
+    state: Arc<SessionState>,
+}
+
+struct SessionState {
+    token: String,
+    session_id: Mutex<Option<String>>,
+    user: Mutex<Option<User>>,
+}
+
+enum SessionEvent {
+    Hello,
+    InvalidSession(bool),
+    Reconnect,
+}
+
+impl Session {
+    pub fn new<S>(token: S) -> Session
+    where
+        S: Into<String>,
+    {
+        Session {
+            state: Arc::new(SessionState {
+                token: token.into(),
+                session_id: Mutex::new(None),
+                user: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn session_id(&self) -> Option<String> {
+        self.state.session_id.lock().clone()
+    }
+
+    /// The user we're logged in as, once READY has been received
+    pub fn user(&self) -> Option<User> {
+        self.state.user.lock().clone()
+    }
+
+    /// Forget the current session so the next login sends a fresh Identify
+    pub fn invalidate(&self) {
+        *self.state.session_id.lock() = None;
+    }
+
+    fn identify(&self) -> Identify {
+        Identify {
+            token: Cow::Borrowed(&self.state.token),
+            properties: IdentifyProperties {
+                os: Cow::Borrowed("windows"),
+                browser: Cow::Borrowed("discord-native-win"),
+                device: Cow::Borrowed("discord-native-win"),
+            },
+            // Payload compression can't be combined with zlib-stream
+            compress: false,
+            large_threshold: Some(250),
+            shard: None,
+            presence: None,
+        }
+    }
+
+    fn login(&self, sequence: &AtomicIsize, sender: &Sender) -> DResult<()> {
+        match (self.session_id(), sequence.load(Ordering::SeqCst)) {
+            (Some(session_id), seq) if seq >= 0 => {
+                let seq = seq as i32;
+                info!("Resuming session {} at sequence {}", session_id, seq);
+                let resume = Resume {
+                    token: Cow::Borrowed(&self.state.token),
+                    session_id: Cow::Owned(session_id),
+                    seq,
+                };
+                sender.send_packet(&Packet::new(resume))
+            }
+            _ => {
+                info!("Identifying new session");
+                sequence.store(NO_SEQUENCE, Ordering::SeqCst);
+                sender.send_packet(&Packet::new(self.identify()))
+            }
+        }
+    }
+
+    fn install(
+        &self,
+        dispatcher: &Dispatcher,
+        events: mpsc::UnboundedSender<SessionEvent>,
+    ) -> Vec<Subscription> {
+        let mut subscriptions = Vec::with_capacity(5);
+
+        let tx = events.clone();
+        subscriptions.push(dispatcher.handle_opcode(move |_: Arc<Hello>| {
+            tx.unbounded_send(SessionEvent::Hello).ok();
+        }));
+
+        let tx = events.clone();
+        subscriptions.push(dispatcher.handle_opcode(move |packet: Arc<InvalidSession>| {
+            tx.unbounded_send(SessionEvent::InvalidSession(packet.0)).ok();
+        }));
+
+        let tx = events;
+        subscriptions.push(dispatcher.handle_opcode(move |_: Arc<Reconnect>| {
+            tx.unbounded_send(SessionEvent::Reconnect).ok();
+        }));
+
+        let state = self.state.clone();
+        subscriptions.push(dispatcher.handle_event(move |ready: Arc<Ready>| {
+            info!(
+                "Session {} ready as {}#{} (gateway v{})",
+                ready.session_id, ready.user.username, ready.user.discriminator, ready.v,
+            );
+            *state.session_id.lock() = Some(ready.session_id.clone());
+            *state.user.lock() = Some(ready.user.clone());
+        }));
+
+        subscriptions.push(dispatcher.handle_event(|resumed: Arc<Resumed>| {
+            info!("Session resumed via {:?}", resumed.trace);
+        }));
+
+        subscriptions
+    }
+}
+
+/// Drives the login flow: Identify or Resume after every Hello, and re-login
+/// when the gateway invalidates the session. Completes once the dispatcher
+/// and its handlers have been dropped.
+///
+/// Handlers are installed immediately, so call this before the gateway has a
+/// chance to send Hello.
+pub fn run(
+    session: Session,
+    dispatcher: Dispatcher,
+    sender: Sender,
+) -> impl Future<Item = (), Error = Error> {
+    let (tx, rx) = mpsc::unbounded();
+    let subscriptions = session.install(&dispatcher, tx);
+    drive_session(session, dispatcher.sequence_counter(), sender, rx, subscriptions)
+}
+
+#[async]
+fn drive_session(
+    session: Session,
+    sequence: Arc<AtomicIsize>,
+    sender: Sender,
+    rx: mpsc::UnboundedReceiver<SessionEvent>,
+    _subscriptions: Vec<Subscription>,
+) -> DResult<()> {
+    #[async]
+    for event in rx.map_err(|_| Error::FutureError) {
+        match event {
+            SessionEvent::Hello => session.login(&sequence, &sender)?,
+            SessionEvent::InvalidSession(resumable) => {
+                warn!("Gateway invalidated the session (resumable: {})", resumable);
+                if !resumable {
+                    session.invalidate();
+                }
+
+                // Discord asks for a random 1-5 second wait before logging in again
+                let wait = Duration::from_millis(thread_rng().gen_range(1000, 5000));
+                await!(Delay::new(Instant::now() + wait))?;
+                session.login(&sequence, &sender)?;
+            }
+            SessionEvent::Reconnect => {
+                info!("Gateway requested a reconnect");
+                // Anything other than a normal close keeps the session resumable
+                sender.send(Message::Close {
+                    status: CloseCode::UnknownError,
+                    reason: Some("reconnect requested".into()),
+                })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Session;
+    use discord::gateway::compression::MessageDeflater;
+    use discord::gateway::dispatcher::NO_SEQUENCE;
+    use discord::gateway::websocket::tests::{next, pair, run};
+    use discord::gateway::websocket::Message;
+    use discord::gateway::{Dispatcher, Sender};
+
+    use std::sync::atomic::Ordering;
+
+    use futures::future;
+    use futures::prelude::*;
+    use serde_json as json;
+
+    /// Logs `session` in with the sequence at `seq`. Returns the packet it
+    /// sent, and the sequence afterwards.
+    fn login(session: Session, seq: isize) -> (json::Value, isize) {
+        let test = pair().and_then(move |(client, server)| {
+            let dispatcher = Dispatcher::new();
+            let sequence = dispatcher.sequence_counter();
+            sequence.store(seq, Ordering::SeqCst);
+
+            let reader = MessageDeflater::new(client.reader);
+            let (writer, _) = dispatcher.attach(reader, client.writer);
+            let sender = Sender::detached();
+            sender.attach(writer);
+
+            future::result(session.login(&sequence, &sender))
+                .and_then(move |()| next(server.reader))
+                .map(move |(message, _)| (message, sequence.load(Ordering::SeqCst)))
+        });
+
+        match run(test).unwrap() {
+            (Message::Text(text), seq) => (json::from_str(&text).unwrap(), seq),
+            (other, _) => panic!("Expected a packet, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn identify_resets_the_sequence() {
+        let session = Session::new("token");
+        let (packet, seq) = login(session.clone(), 41);
+        assert_eq!(packet["op"], 2);
+        assert_eq!(packet["d"]["token"], "token");
+        assert_eq!(seq, NO_SEQUENCE);
+
+        // Resuming picks up where the last connection left off
+        *session.state.session_id.lock() = Some("session".to_string());
+        let (packet, seq) = login(session.clone(), 41);
+        assert_eq!(packet["op"], 6);
+        assert_eq!(packet["d"]["session_id"], "session");
+        assert_eq!(packet["d"]["seq"], 41);
+        assert_eq!(seq, 41);
+
+        // Without a sequence there is nothing to resume from
+        let (packet, _) = login(session, NO_SEQUENCE);
+        assert_eq!(packet["op"], 2);
+    }
+}
@@ -0,0 +1,248 @@
+use discord::gateway::dispatcher::Subscription;
+use error::Error;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::task::AtomicTask;
+use spin::Mutex;
+
+/// Capacity of the streams returned by `Dispatcher::events`
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// What an `EventStream` does with a new item when its buffer is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Discard the oldest buffered item to make room
+    DropOldest,
+    /// Discard the new item
+    DropNewest,
+    /// Buffer the item anyway, and have the gateway reader stop reading until
+    /// the stream has room again. A stream which is never polled will stall
+    /// the whole connection, heartbeat ACKs included.
+    Backpressure,
+}
+
+/// A stream of decoded packets from a `Dispatcher`. The handler feeding it is
+/// removed when the stream is dropped, and the stream ends once the
+/// dispatcher is gone.
+pub struct EventStream<P> {
+    shared: Arc<Shared<P>>,
+    _subscription: Subscription,
+}
+
+/// The sending half of an `EventStream`, owned by the dispatcher handler
+pub struct Producer<P> {
+    shared: Arc<Shared<P>>,
+}
+
+/// The receiving half of an `EventStream`, before its handler is installed
+pub struct Receiver<P> {
+    shared: Arc<Shared<P>>,
+}
+
+/// A stream which may stop the gateway reader while it is full
+pub trait Backpressure: Send + Sync {
+    /// Ready while the stream has room, otherwise the current task is
+    /// notified once it does
+    fn poll_capacity(&self) -> Async<()>;
+}
+
+struct Shared<P> {
+    queue: Mutex<VecDeque<P>>,
+    capacity: usize,
+    overflow: Overflow,
+    closed: AtomicBool,
+    consumer: AtomicTask,
+    reader: AtomicTask,
+}
+
+pub fn channel<P>(capacity: usize, overflow: Overflow) -> (Producer<P>, Receiver<P>)
+where
+    P: Send + 'static,
+{
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: capacity.max(1),
+        overflow,
+        closed: AtomicBool::new(false),
+        consumer: AtomicTask::new(),
+        reader: AtomicTask::new(),
+    });
+
+    let producer = Producer {
+        shared: shared.clone(),
+    };
+    (producer, Receiver { shared })
+}
+
+impl<P> Producer<P> {
+    pub fn push(&self, item: P) {
+        let shared = &self.shared;
+        let mut queue = shared.queue.lock();
+        if queue.len() >= shared.capacity {
+            match shared.overflow {
+                Overflow::DropOldest => {
+                    debug!("Event stream is full, dropping its oldest event");
+                    queue.pop_front();
+                }
+                Overflow::DropNewest => {
+                    debug!("Event stream is full, dropping the new event");
+                    return;
+                }
+                Overflow::Backpressure => (),
+            }
+        }
+
+        queue.push_back(item);
+        drop(queue);
+        shared.consumer.notify();
+    }
+}
+
+impl<P> Receiver<P>
+where
+    P: Send + 'static,
+{
+    pub fn backpressure(&self) -> Arc<Backpressure> {
+        self.shared.clone()
+    }
+
+    /// Finishes the stream. `subscription` is the handler which owns the
+    /// `Producer`.
+    pub fn into_stream(self, subscription: Subscription) -> EventStream<P> {
+        EventStream {
+            shared: self.shared,
+            _subscription: subscription,
+        }
+    }
+}
+
+impl<P> Drop for Producer<P> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.consumer.notify();
+    }
+}
+
+impl<P> Stream for EventStream<P> {
+    type Item = P;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<P>, Error> {
+        let shared = &self.shared;
+        shared.consumer.register();
+
+        let item = shared.queue.lock().pop_front();
+        match item {
+            Some(item) => {
+                shared.reader.notify();
+                Ok(Async::Ready(Some(item)))
+            }
+            None if shared.closed.load(Ordering::SeqCst) => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<P> Backpressure for Shared<P>
+where
+    P: Send,
+{
+    fn poll_capacity(&self) -> Async<()> {
+        if self.overflow != Overflow::Backpressure {
+            return Async::Ready(());
+        }
+
+        self.reader.register();
+        if self.queue.lock().len() < self.capacity {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, EventStream, Overflow, Producer};
+    use discord::gateway::Dispatcher;
+
+    use futures::future;
+    use futures::prelude::*;
+
+    fn with_capacity(capacity: usize, overflow: Overflow) -> (Producer<u32>, EventStream<u32>) {
+        let (producer, receiver) = channel(capacity, overflow);
+        // The test holds the producer, so there is no handler to remove
+        let subscription = Dispatcher::new().handle_close(|_, _| ());
+        (producer, receiver.into_stream(subscription))
+    }
+
+    /// Everything `stream` has buffered, and whether it has ended
+    fn drain(stream: &mut EventStream<u32>) -> (Vec<u32>, bool) {
+        let mut items = vec![];
+        let ended = future::poll_fn(|| loop {
+            match stream.poll()? {
+                Async::Ready(Some(item)) => items.push(item),
+                Async::Ready(None) => return Ok(Async::Ready(true)),
+                Async::NotReady => return Ok(Async::Ready(false)),
+            }
+        });
+        let ended = ended.wait().unwrap();
+        (items, ended)
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (producer, mut stream) = with_capacity(3, Overflow::DropOldest);
+        for i in 1..6 {
+            producer.push(i);
+        }
+        assert_eq!(drain(&mut stream), (vec![3, 4, 5], false));
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (producer, mut stream) = with_capacity(3, Overflow::DropNewest);
+        for i in 1..6 {
+            producer.push(i);
+        }
+        assert_eq!(drain(&mut stream), (vec![1, 2, 3], false));
+
+        // There's always room for one
+        let (producer, mut stream) = with_capacity(0, Overflow::DropNewest);
+        producer.push(1);
+        producer.push(2);
+        assert_eq!(drain(&mut stream), (vec![1], false));
+    }
+
+    #[test]
+    fn backpressure() {
+        let (producer, receiver) = channel(2, Overflow::Backpressure);
+        let backpressure = receiver.backpressure();
+        let subscription = Dispatcher::new().handle_close(|_, _| ());
+        let mut stream = receiver.into_stream(subscription);
+
+        let capacity = || future::lazy(|| Ok::<_, ()>(backpressure.poll_capacity())).wait();
+        producer.push(1);
+        assert!(capacity().unwrap().is_ready());
+
+        // Nothing is dropped, the reader has to wait instead
+        for i in 2..5 {
+            producer.push(i);
+        }
+        assert!(capacity().unwrap().is_not_ready());
+        assert_eq!(drain(&mut stream), (vec![1, 2, 3, 4], false));
+        assert!(capacity().unwrap().is_ready());
+    }
+
+    #[test]
+    fn ends_with_the_producer() {
+        let (producer, mut stream) = with_capacity(3, Overflow::DropOldest);
+        producer.push(1);
+        drop(producer);
+        assert_eq!(drain(&mut stream), (vec![1], true));
+    }
+}
@@ -0,0 +1,159 @@
+use discord::api;
+use discord::gateway::packets::Reconnect;
+use discord::gateway::session::{self, Session};
+use discord::gateway::websocket::CloseCode;
+use discord::gateway::{gateway_uri, heartbeat, new_connection, PartialClient};
+use discord::gateway::{Client, Dispatcher, Sender};
+use error::{DResult, Error};
+use logging::FutureLogExt;
+
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::prelude::*;
+use rand::{thread_rng, Rng};
+use tokio::timer::Delay;
+
+/// Delay before the first reconnect attempt, doubled for every failure after it
+const BASE_BACKOFF_MS: u64 = 1000;
+/// Upper bound on the reconnect delay. A connection which stayed up at least
+/// this long resets the backoff.
+const MAX_BACKOFF_MS: u64 = 60_000;
+/// The window of the gateway's send limit, sat out after a 4008 close
+const RATE_LIMIT_WINDOW_MS: u64 = 60_000;
+
+/// Starts a gateway client which reconnects whenever its connection drops,
+/// resuming the session where the gateway allows it. Handlers installed on
+/// the returned client's dispatcher survive reconnects.
+///
+/// The returned future drives the connection and must be spawned or awaited.
+/// It only completes with an error, once the gateway closes the connection in
+/// a way that retrying can't fix, such as an invalid token.
+pub fn supervise(session: Session) -> (Client, impl Future<Item = (), Error = Error>) {
+    let dispatcher = Dispatcher::new();
+    let sender = Sender::detached();
+    let heartbeat = heartbeat::start(&dispatcher, sender.clone());
+
+    let login = session::run(session.clone(), dispatcher.clone(), sender.clone());
+    tokio::spawn(login.log_errors());
+
+    let client = Client {
+        dispatcher: dispatcher.clone(),
+        sender: sender.clone(),
+        heartbeat: heartbeat.clone(),
+    };
+    let supervisor = run_supervisor(session, dispatcher, sender, heartbeat);
+
+    (client, supervisor)
+}
+
+#[async]
+fn run_supervisor(
+    session: Session,
+    dispatcher: Dispatcher,
+    sender: Sender,
+    heartbeat: heartbeat::Heartbeat,
+) -> DResult<()> {
+    let mut gateway_url: Option<String> = None;
+    let mut failures = 0;
+
+    let reconnect = Arc::new(AtomicBool::new(false));
+    let requested = reconnect.clone();
+    let _reconnect = dispatcher.handle_opcode(move |_: Arc<Reconnect>| {
+        requested.store(true, Ordering::SeqCst);
+    });
+
+    loop {
+        if failures > 0 {
+            let delay = backoff(failures);
+            info!("Reconnecting to the gateway in {:?}", delay);
+            await!(Delay::new(Instant::now() + delay))?;
+        }
+
+        // The gateway url rarely changes, so only ask for it again after it failed us
+        let url = match gateway_url.clone() {
+            Some(url) => url,
+            None => match await!(api::gateway::get()) {
+                Ok(gateway) => {
+                    gateway_url = Some(gateway.url.clone());
+                    gateway.url
+                }
+                Err(e) => {
+                    warn!("Failed to fetch the gateway url: {}", e);
+                    failures += 1;
+                    continue;
+                }
+            },
+        };
+
+        info!("Connecting to {:?}...", url);
+        let PartialClient { reader, writer } = match await!(new_connection(gateway_uri(&url))) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to connect to the gateway: {}", e);
+                gateway_url = None;
+                failures += 1;
+                continue;
+            }
+        };
+
+        let (writer, connection) = dispatcher.attach(reader, writer);
+        sender.attach(writer);
+
+        let connected = Instant::now();
+        // The heartbeat has already queued a Close when it signals
+        let zombie = heartbeat
+            .zombie_signal()
+            .map(|()| None)
+            .or_else(|_| future::empty::<_, Error>());
+        let closed = match await!(connection.select(zombie)) {
+            Ok((closed, _)) => closed,
+            Err((e, _)) => {
+                warn!("Gateway connection failed: {}", e);
+                None
+            }
+        };
+
+        sender.detach();
+        heartbeat.stop();
+
+        // A connection which dropped without a Close frame is treated as
+        // resumable. If the session is gone the gateway will say so after Resume.
+        let (status, reason) = closed.unwrap_or((CloseCode::Abnormal, None));
+        if status.is_fatal() {
+            error!("Gateway closed the connection for good: {}", status);
+            return Err(Error::GatewayClosed(status, reason));
+        } else if status.requires_identify() {
+            session.invalidate();
+        }
+
+        if reconnect.swap(false, Ordering::SeqCst) {
+            info!("Reconnecting right away, as the gateway asked");
+            failures = 0;
+        } else if status == CloseCode::RateLimited {
+            let window = Duration::from_millis(RATE_LIMIT_WINDOW_MS);
+            warn!(
+                "Sent too many packets, waiting {:?} before reconnecting",
+                window
+            );
+            await!(Delay::new(Instant::now() + window))?;
+            failures = 0;
+        } else if connected.elapsed() >= Duration::from_millis(MAX_BACKOFF_MS) {
+            // A session which lasted this long was healthy, so losing it
+            // isn't a failure to back off from
+            failures = 0;
+        } else {
+            failures += 1;
+        }
+    }
+}
+
+/// Exponential backoff, jittered over the upper half of the window
+fn backoff(failures: u32) -> Duration {
+    let exp = cmp::min(failures - 1, 16);
+    let ceiling = cmp::min(BASE_BACKOFF_MS << exp, MAX_BACKOFF_MS);
+    Duration::from_millis(thread_rng().gen_range(ceiling / 2, ceiling + 1))
+}
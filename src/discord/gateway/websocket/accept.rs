@@ -0,0 +1,146 @@
+use discord::gateway::websocket::connect::{hash_sec_key, header_map, read_head};
+use discord::gateway::websocket::Error as WError;
+use discord::gateway::websocket::{Client, DeflateConfig, ServerDecoder, ServerEncoder};
+use error::{DResult, Error};
+
+use std::net::SocketAddr;
+use std::str;
+
+use base64::{self, display::Base64Display};
+use futures::prelude::*;
+use http::HeaderMap;
+use httparse::{Error as HttpError, Request, Status, EMPTY_HEADER};
+use tokio::io::{write_all, AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_io::codec::{FramedRead, FramedWrite};
+
+/// The largest upgrade request that will be accepted from a client
+pub const MAX_REQUEST_SIZE: usize = 16384;
+
+/// Listens on `addr` and runs the server side of the handshake on every
+/// incoming connection. Meant for running a websocket server in-process,
+/// e.g. for tests. Bind to port 0 and use the returned address to get a
+/// free port.
+pub fn listen(
+    addr: &SocketAddr,
+) -> DResult<(SocketAddr, impl Stream<Item = Client, Error = Error>)> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    let clients = listener.incoming().map_err(Error::from).and_then(accept);
+    Ok((local, clients))
+}
+
+/// Answers the upgrade request of a client which connected over `stream`
+#[async]
+pub fn accept<S>(stream: S) -> DResult<Client>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = stream.split();
+    let (head, reader) = await!(read_head(reader, MAX_REQUEST_SIZE))?;
+
+    let (accept_key, headers) = match check_request(&head) {
+        Ok(checked) => checked,
+        Err(e) => {
+            let _ = await!(write_all(writer, "HTTP/1.1 400 Bad Request\r\n\r\n"));
+            return Err(e.into());
+        }
+    };
+
+    // The only extension there is, so take the first offer of it that works
+    let deflate = headers
+        .get_all("sec-websocket-extensions")
+        .iter()
+        .filter_map(|offers| DeflateConfig::accept(offers.as_bytes()))
+        .next();
+    let extensions = match deflate {
+        Some(ref config) => format!("Sec-WebSocket-Extensions: {}\r\n", config.response()),
+        None => String::new(),
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {accept}\r\n\
+         {extensions}\
+         \r\n",
+        accept = Base64Display::standard(&accept_key),
+        extensions = extensions,
+    );
+    let (writer, _) = await!(write_all(writer, response))?;
+
+    let mut decoder = ServerDecoder::new();
+    let mut encoder = ServerEncoder::new();
+    if let Some(ref config) = deflate {
+        decoder = decoder.with_deflate(config);
+        encoder = encoder.with_deflate(config);
+    }
+    let reader = FramedRead::new(reader, decoder);
+    let writer = FramedWrite::new(writer, encoder);
+
+    Ok(Client::from_halves(reader, writer, headers))
+}
+
+/// Validates the upgrade request. Returns the hashed key to answer it with,
+/// and the request's headers.
+fn check_request(head: &[u8]) -> Result<([u8; 20], HeaderMap), WError> {
+    // Start with room for plenty of headers, and grow until they all fit
+    let mut capacity = 32;
+    loop {
+        let mut headers = vec![EMPTY_HEADER; capacity];
+        let mut req = Request::new(&mut headers);
+        match req.parse(head) {
+            Ok(Status::Complete(_)) => return check_upgrade(&req),
+            Ok(Status::Partial) => return Err(WError::InvalidRequest),
+            Err(HttpError::TooManyHeaders) => capacity *= 2,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn check_upgrade(req: &Request) -> Result<([u8; 20], HeaderMap), WError> {
+    // RFC 6455 Section 4.2.1
+    if req.method != Some("GET") || req.version != Some(1) {
+        return Err(WError::InvalidRequest);
+    }
+
+    let mut has_upgrade = false;
+    let mut has_connection = false;
+    let mut has_version = false;
+    let mut sec_key = None;
+
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Upgrade") {
+            if header.value.eq_ignore_ascii_case(b"websocket") {
+                has_upgrade = true;
+            }
+        } else if header.name.eq_ignore_ascii_case("Connection") {
+            for tok in header.value.split(|&b| b == b',') {
+                let tok = str::from_utf8(tok).map(str::trim).unwrap_or("");
+                if tok.eq_ignore_ascii_case("upgrade") {
+                    has_connection = true;
+                }
+            }
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Version") {
+            has_version = header.value == b"13";
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+            sec_key = str::from_utf8(header.value).map(str::trim).ok();
+        }
+    }
+
+    if !has_upgrade || !has_connection || !has_version {
+        return Err(WError::InvalidRequest);
+    }
+
+    let key = sec_key
+        .and_then(|k| base64::decode(k).ok())
+        .ok_or(WError::InvalidRequest)?;
+    if key.len() != 16 {
+        return Err(WError::InvalidRequest);
+    }
+
+    let mut raw_key = [0; 16];
+    raw_key.copy_from_slice(&key);
+    Ok((hash_sec_key(&raw_key), header_map(req.headers)))
+}
@@ -1,6 +1,8 @@
 use discord::gateway::websocket::{Error, Message};
 
 use futures::prelude::*;
+use futures::sync::BiLock;
+use http::HeaderMap;
 
 //pub type Reader = FramedRead<BufReader<Box<AsyncRead + Send>>, ClientDecoder>;
 pub type Reader = Box<Stream<Item = Message, Error = Error> + Send + 'static>;
@@ -10,4 +12,124 @@ pub type Writer = Box<Sink<SinkItem = Message, SinkError = Error> + Send + 'stat
 pub struct Client {
     pub reader: Reader,
     pub writer: Writer,
+    /// Headers the other side sent during the handshake, for diagnostics
+    pub headers: HeaderMap,
+}
+
+impl Client {
+    /// Puts a client together from the two halves of a connection. The
+    /// reader shares the writer so it can fail the connection with a Close
+    /// when the other side breaks the protocol.
+    pub fn from_halves<R, W>(reader: R, writer: W, headers: HeaderMap) -> Client
+    where
+        R: Stream<Item = Message, Error = Error> + Send + 'static,
+        W: Sink<SinkItem = Message, SinkError = Error> + Send + 'static,
+    {
+        let (writer, reader_writer) = BiLock::new(writer);
+        let reader = FailingReader {
+            reader,
+            writer: reader_writer,
+            close: None,
+            error: None,
+        };
+
+        Client {
+            reader: Box::new(reader),
+            writer: Box::new(SharedWriter(writer)),
+            headers,
+        }
+    }
+}
+
+/// Sends a Close before passing on read errors which have a close code
+/// (RFC 6455 Section 7.1.7)
+struct FailingReader<R, W> {
+    reader: R,
+    writer: BiLock<W>,
+    close: Option<Message>,
+    error: Option<Error>,
+}
+
+impl<R, W> Stream for FailingReader<R, W>
+where
+    R: Stream<Item = Message, Error = Error>,
+    W: Sink<SinkItem = Message, SinkError = Error>,
+{
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Message>, Error> {
+        if self.error.is_none() {
+            match self.reader.poll() {
+                Err(e) => match e.close_code() {
+                    Some(status) => {
+                        warn!("Failing the connection with {}: {}", status, e);
+                        self.close = Some(Message::Close {
+                            status,
+                            reason: None,
+                        });
+                        self.error = Some(e);
+                    }
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+
+        let mut writer = match self.writer.poll_lock() {
+            Async::Ready(writer) => writer,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        if let Some(close) = self.close.take() {
+            match writer.start_send(close) {
+                Ok(AsyncSink::Ready) => (),
+                Ok(AsyncSink::NotReady(close)) => {
+                    self.close = Some(close);
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => debug!("Failed to send Close: {}", e),
+            }
+        }
+
+        match writer.poll_complete() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => (),
+            Err(e) => debug!("Failed to send Close: {}", e),
+        }
+
+        Err(self.error.take().unwrap())
+    }
+}
+
+/// The half of the writer handed out to users
+struct SharedWriter<W>(BiLock<W>);
+
+impl<W> Sink for SharedWriter<W>
+where
+    W: Sink<SinkItem = Message, SinkError = Error>,
+{
+    type SinkItem = Message;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Message) -> StartSend<Message, Error> {
+        match self.0.poll_lock() {
+            Async::Ready(mut writer) => writer.start_send(item),
+            Async::NotReady => Ok(AsyncSink::NotReady(item)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        match self.0.poll_lock() {
+            Async::Ready(mut writer) => writer.poll_complete(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        match self.0.poll_lock() {
+            Async::Ready(mut writer) => writer.close(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
 }
@@ -0,0 +1,276 @@
+use std::fmt;
+
+/// Status codes carried by Close frames. Covers the codes defined by
+/// RFC 6455 and the ones Discord's gateway uses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CloseCode {
+    // RFC 6455 Section 7.4.1
+    Normal,
+    GoingAway,
+    ProtocolError,
+    Unsupported,
+    /// Reserved for when a Close frame had no status code. Never sent.
+    NoStatus,
+    /// Reserved for when the connection dropped without a Close frame. Never sent.
+    Abnormal,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    MandatoryExtension,
+    InternalError,
+    ServiceRestart,
+    TryAgainLater,
+    BadGateway,
+    /// Reserved for TLS handshake failures. Never sent.
+    TlsHandshake,
+
+    // Discord gateway
+    UnknownError,
+    UnknownOpcode,
+    DecodeError,
+    NotAuthenticated,
+    AuthenticationFailed,
+    AlreadyAuthenticated,
+    InvalidSequence,
+    RateLimited,
+    SessionTimedOut,
+    InvalidShard,
+    ShardingRequired,
+    InvalidApiVersion,
+    InvalidIntents,
+    DisallowedIntents,
+
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn code(self) -> u16 {
+        self.into()
+    }
+
+    /// Whether the gateway will never accept the same login again, so
+    /// reconnecting is pointless
+    pub fn is_fatal(self) -> bool {
+        match self {
+            CloseCode::AuthenticationFailed
+            | CloseCode::InvalidShard
+            | CloseCode::ShardingRequired
+            | CloseCode::InvalidApiVersion
+            | CloseCode::InvalidIntents
+            | CloseCode::DisallowedIntents => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the session is gone and the next connection has to Identify
+    pub fn requires_identify(self) -> bool {
+        match self {
+            CloseCode::NotAuthenticated
+            | CloseCode::InvalidSequence
+            | CloseCode::SessionTimedOut => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the next connection can Resume the session
+    pub fn is_resumable(self) -> bool {
+        !self.is_fatal() && !self.requires_identify()
+    }
+
+    /// Whether this code may appear in a Close frame on the wire
+    pub fn is_sendable(self) -> bool {
+        match self {
+            CloseCode::NoStatus | CloseCode::Abnormal | CloseCode::TlsHandshake => false,
+            _ => true,
+        }
+    }
+
+    /// Whether `code` may be received in a Close frame. Codes under 3000 are
+    /// only allowed if RFC 6455 or the IANA registry assign them for use on
+    /// the wire (RFC 6455 Section 7.4.2)
+    pub fn is_valid_code(code: u16) -> bool {
+        match code {
+            1000..=1003 | 1007..=1014 | 3000..=4999 => true,
+            _ => false,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            CloseCode::Normal => "normal closure",
+            CloseCode::GoingAway => "going away",
+            CloseCode::ProtocolError => "protocol error",
+            CloseCode::Unsupported => "unsupported data",
+            CloseCode::NoStatus => "no status code",
+            CloseCode::Abnormal => "connection dropped",
+            CloseCode::InvalidPayload => "invalid payload data",
+            CloseCode::PolicyViolation => "policy violation",
+            CloseCode::MessageTooBig => "message too big",
+            CloseCode::MandatoryExtension => "missing mandatory extension",
+            CloseCode::InternalError => "internal server error",
+            CloseCode::ServiceRestart => "service restarting",
+            CloseCode::TryAgainLater => "try again later",
+            CloseCode::BadGateway => "bad gateway",
+            CloseCode::TlsHandshake => "TLS handshake failed",
+            CloseCode::UnknownError => "unknown error",
+            CloseCode::UnknownOpcode => "unknown opcode",
+            CloseCode::DecodeError => "payload decode error",
+            CloseCode::NotAuthenticated => "not authenticated",
+            CloseCode::AuthenticationFailed => "authentication failed",
+            CloseCode::AlreadyAuthenticated => "already authenticated",
+            CloseCode::InvalidSequence => "invalid resume sequence",
+            CloseCode::RateLimited => "rate limited",
+            CloseCode::SessionTimedOut => "session timed out",
+            CloseCode::InvalidShard => "invalid shard",
+            CloseCode::ShardingRequired => "sharding required",
+            CloseCode::InvalidApiVersion => "invalid API version",
+            CloseCode::InvalidIntents => "invalid intents",
+            CloseCode::DisallowedIntents => "disallowed intents",
+            CloseCode::Other(_) => "unknown close code",
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1014 => CloseCode::BadGateway,
+            1015 => CloseCode::TlsHandshake,
+            4000 => CloseCode::UnknownError,
+            4001 => CloseCode::UnknownOpcode,
+            4002 => CloseCode::DecodeError,
+            4003 => CloseCode::NotAuthenticated,
+            4004 => CloseCode::AuthenticationFailed,
+            4005 => CloseCode::AlreadyAuthenticated,
+            4007 => CloseCode::InvalidSequence,
+            4008 => CloseCode::RateLimited,
+            4009 => CloseCode::SessionTimedOut,
+            4010 => CloseCode::InvalidShard,
+            4011 => CloseCode::ShardingRequired,
+            4012 => CloseCode::InvalidApiVersion,
+            4013 => CloseCode::InvalidIntents,
+            4014 => CloseCode::DisallowedIntents,
+            other => CloseCode::Other(other),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::UnknownError => 4000,
+            CloseCode::UnknownOpcode => 4001,
+            CloseCode::DecodeError => 4002,
+            CloseCode::NotAuthenticated => 4003,
+            CloseCode::AuthenticationFailed => 4004,
+            CloseCode::AlreadyAuthenticated => 4005,
+            CloseCode::InvalidSequence => 4007,
+            CloseCode::RateLimited => 4008,
+            CloseCode::SessionTimedOut => 4009,
+            CloseCode::InvalidShard => 4010,
+            CloseCode::ShardingRequired => 4011,
+            CloseCode::InvalidApiVersion => 4012,
+            CloseCode::InvalidIntents => 4013,
+            CloseCode::DisallowedIntents => 4014,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} ({})", self.description(), self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CloseCode;
+
+    use std::collections::HashSet;
+
+    /// Every code with a name of its own
+    fn named_codes() -> Vec<(u16, CloseCode)> {
+        (0..=u16::max_value())
+            .map(|code| (code, CloseCode::from(code)))
+            .filter(|&(_, close)| close != CloseCode::Other(close.code()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for code in 0..=u16::max_value() {
+            assert_eq!(CloseCode::from(code).code(), code);
+        }
+
+        // No two codes share a name
+        let named = named_codes();
+        let names: HashSet<_> = named.iter().map(|&(_, close)| close).collect();
+        assert_eq!(names.len(), named.len());
+        assert_eq!(named.len(), 29);
+    }
+
+    #[test]
+    fn gateway_codes() {
+        let fatal = [4004, 4010, 4011, 4012, 4013, 4014];
+        let identify = [4003, 4007, 4009];
+
+        for (code, close) in named_codes() {
+            assert_eq!(close.is_fatal(), fatal.contains(&code), "{}", close);
+            assert_eq!(
+                close.requires_identify(),
+                identify.contains(&code),
+                "{}",
+                close
+            );
+            assert_eq!(
+                close.is_resumable(),
+                !fatal.contains(&code) && !identify.contains(&code),
+                "{}",
+                close
+            );
+        }
+
+        assert!(CloseCode::from(4008).is_resumable());
+        assert!(CloseCode::Other(4999).is_resumable());
+    }
+
+    #[test]
+    fn reserved_codes() {
+        for &code in &[1005, 1006, 1015] {
+            let close = CloseCode::from(code);
+            assert!(!close.is_sendable(), "{}", close);
+            assert!(!CloseCode::is_valid_code(code), "{}", close);
+        }
+        assert!(CloseCode::Normal.is_sendable());
+        assert_eq!(CloseCode::Abnormal.to_string(), "connection dropped (1006)");
+    }
+}
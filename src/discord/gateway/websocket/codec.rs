@@ -1,9 +1,14 @@
-use discord::gateway::websocket::{Error, Frame, Message, OpCode};
+use discord::gateway::websocket::deflate::{DeflateConfig, Deflater, Inflater};
+use discord::gateway::websocket::frame::FrameFlags;
+use discord::gateway::websocket::{CloseCode, Error, Frame, Message, OpCode};
 
+use std::cmp;
+use std::collections::VecDeque;
 use std::str;
+use std::usize;
 
 use byteorder::{BigEndian, ByteOrder};
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use rand::{thread_rng, Rng};
 use tokio_io::codec::{Decoder, Encoder};
 
@@ -30,35 +35,159 @@ impl Decoder for ClientCodec {
     }
 }
 
-pub struct ClientEncoder;
+pub struct ServerCodec {
+    pub encoder: ServerEncoder,
+    pub decoder: ServerDecoder,
+}
 
-impl Encoder for ClientEncoder {
+impl ServerCodec {
+    pub fn new() -> Self {
+        ServerCodec {
+            encoder: ServerEncoder::new(),
+            decoder: ServerDecoder::new(),
+        }
+    }
+}
+
+impl Encoder for ServerCodec {
     type Item = Message;
     type Error = Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Error> {
-        let frame = item.to_frame().with_mask(thread_rng().gen());
-
-        if frame.payload.len() > 4096 {
-            error!(
-                "Attempted to send frame larger than 4096 bytes. This would be \
-                 rejected by the gateway and so was squashed.\n    frame data: {:?}",
-                String::from_utf8_lossy(&frame.payload)
-            );
-            return Ok(());
+        self.encoder.encode(item, dst)
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        self.decoder.decode(src)
+    }
+}
+
+pub struct ClientEncoder {
+    max_fragment_size: usize,
+    deflater: Option<Deflater>,
+    mask: bool,
+}
+
+impl ClientEncoder {
+    pub fn new() -> Self {
+        ClientEncoder::with_fragment_size(4096)
+    }
+
+    /// Text and binary messages with payloads over `max_fragment` bytes are
+    /// split into continuation frames
+    pub fn with_fragment_size(max_fragment: usize) -> Self {
+        ClientEncoder {
+            max_fragment_size: max_fragment.max(1),
+            deflater: None,
+            mask: true,
         }
+    }
 
+    /// Compresses text and binary messages with the negotiated
+    /// permessage-deflate parameters
+    pub fn with_deflate(mut self, config: &DeflateConfig) -> Self {
+        self.deflater = Some(Deflater::new(config));
+        self
+    }
+
+    fn encode_frame(&self, frame: Frame, dst: &mut BytesMut) {
+        let frame = if self.mask {
+            frame.with_mask(thread_rng().gen())
+        } else {
+            frame
+        };
         let size = frame.frame_size();
         dst.reserve(size);
         frame.encode(dst);
+    }
+}
+
+impl Encoder for ClientEncoder {
+    type Item = Message;
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        item.validate()?;
+        let mut frame = item.to_frame()?;
+        let opcode = frame.flags.opcode();
+        let is_data = match opcode {
+            OpCode::Text | OpCode::Binary => true,
+            _ => false,
+        };
+
+        if let (true, Some(deflater)) = (is_data, self.deflater.as_mut()) {
+            frame.payload = deflater.compress(&frame.payload)?.into();
+            frame.flags.set_rsv1(true);
+        }
+
+        if !is_data || frame.payload.len() <= self.max_fragment_size {
+            self.encode_frame(frame, dst);
+            return Ok(());
+        }
+
+        // Only the first fragment says whether the message is compressed
+        let compressed = frame.flags.rsv1();
+        let len = frame.payload.len();
+        let size = self.max_fragment_size;
+        let count = (len + size - 1) / size;
+        for i in 0..count {
+            let chunk = frame.payload.slice(i * size, cmp::min((i + 1) * size, len));
+            let opcode = if i == 0 { opcode } else { OpCode::Continuation };
+            let mut part = Frame::new(opcode, chunk).with_final(i + 1 == count);
+            part.flags.set_rsv1(i == 0 && compressed);
+            self.encode_frame(part, dst);
+        }
         Ok(())
     }
 }
 
+/// Encodes frames the way a server sends them, which is without a mask
+pub struct ServerEncoder(ClientEncoder);
+
+impl ServerEncoder {
+    pub fn new() -> Self {
+        ServerEncoder::with_fragment_size(4096)
+    }
+
+    pub fn with_fragment_size(max_fragment: usize) -> Self {
+        let mut encoder = ClientEncoder::with_fragment_size(max_fragment);
+        encoder.mask = false;
+        ServerEncoder(encoder)
+    }
+
+    /// Compresses text and binary messages with the permessage-deflate
+    /// parameters agreed with the client
+    pub fn with_deflate(self, config: &DeflateConfig) -> Self {
+        ServerEncoder(self.0.with_deflate(&config.reversed()))
+    }
+}
+
+impl Encoder for ServerEncoder {
+    type Item = Message;
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        self.0.encode(item, dst)
+    }
+}
+
 pub struct ClientDecoder {
     max_recv_message_size: usize,
+    max_frame_size: usize,
     decode_frames: Vec<Frame>,
     decoded_size: usize,
+    inflater: Option<Inflater>,
+    compressed: bool,
+    masked: bool,
+    streaming: bool,
+    streamed: Option<OpCode>,
+    utf8: Utf8Check,
+    parts: VecDeque<Message>,
 }
 
 impl ClientDecoder {
@@ -66,32 +195,140 @@ impl ClientDecoder {
         ClientDecoder::with_limit(1_000_000)
     }
 
+    /// Limits messages, and each of their frames, to `max_recv` bytes
     pub fn with_limit(max_recv: usize) -> Self {
         ClientDecoder {
             max_recv_message_size: max_recv,
+            max_frame_size: max_recv,
             decode_frames: Vec::with_capacity(16),
             decoded_size: 0,
+            inflater: None,
+            compressed: false,
+            masked: false,
+            streaming: false,
+            streamed: None,
+            utf8: Utf8Check::default(),
+            parts: VecDeque::new(),
         }
     }
 
+    /// Limits each frame to `max_frame` bytes of payload, separately from
+    /// the limit on whole messages
+    pub fn with_frame_limit(mut self, max_frame: usize) -> Self {
+        self.max_frame_size = max_frame;
+        self
+    }
+
+    /// Decompresses messages sent with RSV1 set, using the negotiated
+    /// permessage-deflate parameters
+    pub fn with_deflate(mut self, config: &DeflateConfig) -> Self {
+        self.inflater = Some(Inflater::new(config));
+        self
+    }
+
+    /// Yields text and binary messages as a `Start`, `Chunk`s of their
+    /// payload as frames arrive, and an `End`, instead of buffering them
+    /// whole. Control frames still come whole, and may arrive between the
+    /// chunks of a message.
+    ///
+    /// Since nothing holds a streamed message whole, only its frames are
+    /// limited, and a compressed frame may inflate to at most the message
+    /// limit.
+    pub fn streaming(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    /// Checks a frame's header, which is enough to reject malformed or
+    /// oversized frames before their payload has arrived
+    fn check_header(&self, src: &BytesMut) -> Result<(), Error> {
+        let flags = FrameFlags(src[0]);
+        let masked = src[1] & 0b1000_0000 != 0;
+        let len = src[1] & 0b0111_1111;
+
+        // No extension which uses RSV2 or RSV3 is ever negotiated
+        if flags.rsv2() || flags.rsv3() {
+            return Err(Error::ReservedBitsSet);
+        }
+
+        // RSV1 marks a compressed message, and only goes on its first frame
+        if flags.rsv1() {
+            let starts_message = match flags.opcode() {
+                OpCode::Text | OpCode::Binary => !self.in_message(),
+                _ => false,
+            };
+            if !starts_message || self.inflater.is_none() {
+                return Err(Error::UnexpectedCompression);
+            }
+        }
+
+        // RFC 6455 Section 5.1, clients mask every frame and servers none
+        if masked != self.masked {
+            return Err(if self.masked {
+                Error::UnmaskedFrame
+            } else {
+                Error::MaskedFrame
+            });
+        }
+
+        // RFC 6455 Section 5.5
+        if flags.opcode() as u8 & CONTROL_OPCODE != 0 {
+            if len > 125 {
+                return Err(Error::ControlFrameTooLarge);
+            }
+            if !flags.is_final() {
+                return Err(Error::NonFinalControlFrame);
+            }
+        }
+
+        // Checked before waiting for the payload, so a hostile length can't
+        // make the read buffer grow without bound
+        if let Some((_, len)) = Frame::lengths(src) {
+            // RFC 6455 Section 5.2, 64-bit lengths leave the top bit clear
+            if len >> 63 != 0 {
+                return Err(Error::InvalidFrameLength(len));
+            }
+            if len > self.max_frame_size as u64 {
+                return Err(Error::OversizedFrame(len));
+            }
+            let combined_size = (self.decoded_size as u64).saturating_add(len);
+            if !self.streaming && combined_size > self.max_recv_message_size as u64 {
+                let size = cmp::min(combined_size, usize::MAX as u64) as usize;
+                return Err(Error::FrameTooLarge(size));
+            }
+        }
+
+        Ok(())
+    }
+
     fn clear_state(&mut self) {
         self.decode_frames.clear();
         self.decoded_size = 0;
+        self.compressed = false;
+        self.streamed = None;
+        self.utf8.partial.clear();
     }
 
-    fn drain_frames(&mut self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(self.decoded_size);
+    /// Joins the payloads of a fragmented message. Unfragmented messages
+    /// keep their payload without a copy.
+    fn drain_frames(&mut self) -> Bytes {
+        let size = self.decoded_size;
         self.decoded_size = 0;
+        if self.decode_frames.len() == 1 {
+            return self.decode_frames.pop().unwrap().payload;
+        }
+
+        let mut buf = BytesMut::with_capacity(size);
         for frame in self.decode_frames.drain(..) {
             buf.extend_from_slice(&frame.payload);
         }
-        buf
+        buf.freeze()
     }
 
     fn combine_frames(&mut self) -> Result<Message, Error> {
         let opcode = self.decode_frames[0].flags.opcode();
 
-        let buf = match opcode {
+        let mut buf = match opcode {
             OpCode::Text | OpCode::Binary => self.drain_frames(),
             op => {
                 self.clear_state();
@@ -99,38 +336,42 @@ impl ClientDecoder {
             }
         };
 
+        if self.compressed {
+            self.compressed = false;
+            let limit = self.max_recv_message_size;
+            match self.inflater {
+                Some(ref mut inflater) => buf = inflater.decompress(&buf, limit)?.into(),
+                None => return Err(Error::UnexpectedCompression),
+            }
+        }
+
         match opcode {
             OpCode::Text => {
-                let text = String::from_utf8(buf).map_err(|_| Error::TextFrameNotUtf8)?;
-                Ok(Message::Text(text))
+                let text = str::from_utf8(&buf).map_err(|_| Error::TextFrameNotUtf8)?;
+                Ok(Message::Text(text.to_string()))
             }
             OpCode::Binary => Ok(Message::Binary(buf)),
             _ => unreachable!(),
         }
     }
-}
-
-impl Decoder for ClientDecoder {
-    type Item = Message;
-    type Error = Error;
-
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
-        let (flags, len) = match Frame::is_complete(src) {
-            Some(info) => info,
-            None => return Ok(None),
-        };
-
-        let combined_size = self.decoded_size.saturating_add(len);
-        if combined_size > self.max_recv_message_size {
-            self.clear_state();
-            return Err(Error::FrameTooLarge(combined_size));
-        }
 
+    /// Decodes the complete frame at the start of `src`. Returns a message
+    /// if the frame finished one.
+    fn decode_frame(
+        &mut self,
+        src: &mut BytesMut,
+        flags: FrameFlags,
+        len: usize,
+    ) -> Result<Option<Message>, Error> {
         let msg = match flags.opcode() {
-            OpCode::Continuation if !self.decode_frames.is_empty() => {
-                let frame = Frame::decode(src).unmask();
-                self.decode_frames.push(frame);
+            OpCode::Continuation if self.in_message() => {
+                let frame = Frame::decode(src);
+                if self.streaming {
+                    self.stream_frame(frame)?;
+                    return Ok(None);
+                }
                 self.decoded_size += len;
+                self.decode_frames.push(frame);
 
                 if flags.is_final() {
                     Some(self.combine_frames()?)
@@ -139,10 +380,15 @@ impl Decoder for ClientDecoder {
                 }
             }
 
-            OpCode::Text | OpCode::Binary if self.decode_frames.is_empty() => {
-                let frame = Frame::decode(src).unmask();
-                self.decode_frames.push(frame);
+            OpCode::Text | OpCode::Binary if !self.in_message() => {
+                let frame = Frame::decode(src);
+                if self.streaming {
+                    self.stream_frame(frame)?;
+                    return Ok(None);
+                }
                 self.decoded_size += len;
+                self.compressed = flags.rsv1();
+                self.decode_frames.push(frame);
 
                 if flags.is_final() {
                     Some(self.combine_frames()?)
@@ -152,20 +398,24 @@ impl Decoder for ClientDecoder {
             }
 
             OpCode::Close | OpCode::Ping | OpCode::Pong => {
-                if !flags.is_final() {
-                    return Err(Error::NonFinalControlFrame);
-                }
-
-                let frame = Frame::decode(src).unmask();
+                let frame = Frame::decode(src);
                 match frame.flags.opcode() {
                     OpCode::Close => {
-                        let mut status = 0;
+                        let mut status = CloseCode::NoStatus;
                         let mut reason = None;
+                        if frame.payload.len() == 1 {
+                            return Err(Error::BadCloseFrame);
+                        }
                         if frame.payload.len() >= 2 {
-                            status = BigEndian::read_u16(&frame.payload[..2]);
+                            let code = BigEndian::read_u16(&frame.payload[..2]);
+                            if !CloseCode::is_valid_code(code) {
+                                return Err(Error::InvalidCloseCode(code));
+                            }
+                            status = code.into();
                         }
                         if frame.payload.len() > 2 {
-                            reason = str::from_utf8(&frame.payload[2..]).map(|s| s.into()).ok();
+                            let text = str::from_utf8(&frame.payload[2..]);
+                            reason = Some(text.map_err(|_| Error::TextFrameNotUtf8)?.into());
                         }
                         Some(Message::Close { status, reason })
                     }
@@ -176,19 +426,171 @@ impl Decoder for ClientDecoder {
             }
 
             op => {
-                if self.decode_frames.is_empty() {
-                    return Err(Error::UnexpectedFrame(op, &VALID_START_CODES));
-                } else {
+                if self.in_message() {
                     return Err(Error::UnexpectedFrame(op, &VALID_CONTINUATION_CODES));
+                } else {
+                    return Err(Error::UnexpectedFrame(op, &VALID_START_CODES));
                 }
             }
         };
 
-        *src = src.split_off(len);
         Ok(msg)
     }
+
+    /// Queues a frame of the message being streamed as a chunk, preceded by
+    /// `Start` or followed by `End` as needed
+    fn stream_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        let opcode = frame.flags.opcode();
+        let is_final = frame.flags.is_final();
+        if opcode != OpCode::Continuation {
+            self.streamed = Some(opcode);
+            self.compressed = frame.flags.rsv1();
+            self.parts.push_back(Message::Start(opcode));
+        }
+
+        let mut chunk = frame.payload;
+        if self.compressed {
+            let limit = self.max_recv_message_size;
+            match self.inflater {
+                Some(ref mut inflater) => {
+                    chunk = inflater.decompress_part(&chunk, is_final, limit)?.into()
+                }
+                None => return Err(Error::UnexpectedCompression),
+            }
+        }
+        if self.streamed == Some(OpCode::Text) {
+            self.utf8.check(&chunk, is_final)?;
+        }
+
+        if !chunk.is_empty() {
+            self.parts.push_back(Message::Chunk(chunk));
+        }
+        if is_final {
+            self.parts.push_back(Message::End);
+            self.clear_state();
+        }
+        Ok(())
+    }
+
+    /// Whether frames of a fragmented message are still expected
+    fn in_message(&self) -> bool {
+        !self.decode_frames.is_empty() || self.streamed.is_some()
+    }
 }
 
+impl Decoder for ClientDecoder {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        // Frames which don't finish a message yield nothing, so keep going
+        // until one does or the buffer runs out
+        loop {
+            if let Some(part) = self.parts.pop_front() {
+                return Ok(Some(part));
+            }
+
+            if src.len() >= 2 {
+                if let Err(e) = self.check_header(src) {
+                    self.clear_state();
+                    return Err(e);
+                }
+            }
+
+            let (flags, len) = match Frame::is_complete(src) {
+                Some(info) => info,
+                None => return Ok(None),
+            };
+
+            if let Some(message) = self.decode_frame(src, flags, len)? {
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+/// Validates text which arrives in chunks, any of which may end partway
+/// through a character
+#[derive(Default)]
+struct Utf8Check {
+    partial: Vec<u8>,
+}
+
+impl Utf8Check {
+    fn check(&mut self, mut data: &[u8], last: bool) -> Result<(), Error> {
+        // Finish the character the previous chunk ended in
+        if !self.partial.is_empty() {
+            let width = utf8_width(self.partial[0]);
+            let take = cmp::min(width - self.partial.len(), data.len());
+            self.partial.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.partial.len() == width {
+                str::from_utf8(&self.partial).map_err(|_| Error::TextFrameNotUtf8)?;
+                self.partial.clear();
+            }
+        }
+
+        if self.partial.is_empty() {
+            if let Err(e) = str::from_utf8(data) {
+                // Only an incomplete character at the very end may continue
+                if e.error_len().is_some() {
+                    return Err(Error::TextFrameNotUtf8);
+                }
+                self.partial.extend_from_slice(&data[e.valid_up_to()..]);
+            }
+        }
+
+        if last && !self.partial.is_empty() {
+            self.partial.clear();
+            return Err(Error::TextFrameNotUtf8);
+        }
+        Ok(())
+    }
+}
+
+/// Length of the character a UTF-8 lead byte starts
+fn utf8_width(lead: u8) -> usize {
+    match lead {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 1,
+    }
+}
+
+/// Decodes frames sent by a client, all of which have to be masked
+pub struct ServerDecoder(ClientDecoder);
+
+impl ServerDecoder {
+    pub fn new() -> Self {
+        ServerDecoder::with_limit(1_000_000)
+    }
+
+    pub fn with_limit(max_recv: usize) -> Self {
+        let mut decoder = ClientDecoder::with_limit(max_recv);
+        decoder.masked = true;
+        ServerDecoder(decoder)
+    }
+
+    /// Decompresses messages sent with RSV1 set, using the
+    /// permessage-deflate parameters agreed with the client
+    pub fn with_deflate(self, config: &DeflateConfig) -> Self {
+        ServerDecoder(self.0.with_deflate(&config.reversed()))
+    }
+}
+
+impl Decoder for ServerDecoder {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        self.0.decode(src)
+    }
+}
+
+/// Set in the opcode of Close, Ping, Pong and the reserved control frames
+const CONTROL_OPCODE: u8 = 0b1000;
+
 static VALID_START_CODES: &'static [OpCode] = &[
     OpCode::Text,
     OpCode::Binary,
@@ -1,48 +1,142 @@
 use discord::gateway::websocket::Error as WError;
 use discord::gateway::websocket::{Client, ClientDecoder, ClientEncoder};
+use discord::gateway::websocket::{DeflateConfig, DeflateSettings};
+use discord::gateway::websocket::TlsSettings;
+use discord::net;
+use discord::proxy::{self, ProxySettings};
 use error::{DResult, Error};
 
+use std::cmp;
 use std::fmt::{self, Write};
-use std::io::BufReader;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::{self, Read};
 use std::str;
+use std::time::{Duration, Instant};
 
 use base64::display::Base64Display;
 use either::Either;
+use futures::future::Either as FutureEither;
 use futures::prelude::*;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use http::uri::{Scheme, Uri};
-use httparse::{Response, Status, EMPTY_HEADER};
-use native_tls::TlsConnector;
+use httparse::{Error as HttpError, Header, Response, Status, EMPTY_HEADER};
 use rand::{thread_rng, Rng};
 use sha1::Sha1;
-use tokio::io::{read_until, write_all, AsyncRead, AsyncWrite};
+use tokio::io::{read, write_all, AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::timer::Deadline;
 use tokio_io::codec::{FramedRead, FramedWrite};
 use tokio_io::io::{ReadHalf, WriteHalf};
 use tokio_tls::{TlsConnectorExt, TlsStream};
 
 pub struct ConnectSettings {
-    /// The largest frame that will be accepted from the server.
-    /// Default is 1_000_000 bytes
+    /// The largest message that will be accepted from the server, all of
+    /// its frames together. Default is 1_000_000 bytes
     pub max_websocket_frame: usize,
+    /// The largest payload of a single frame that will be accepted from the
+    /// server. Default is 1_000_000 bytes
+    pub max_frame_size: usize,
+    /// The largest payload sent in a single frame before a message is split
+    /// into continuation frames. Default is 4096 bytes
+    pub max_fragment_size: usize,
+    /// Read text and binary messages as `Start`, `Chunk`s and `End` rather
+    /// than whole. Default is off
+    pub stream_messages: bool,
+    /// Offer permessage-deflate to the server. Default is off, since the
+    /// Discord gateway compresses its payloads on its own
+    pub deflate: Option<DeflateSettings>,
+    /// Proxy to connect through. Default is read from the environment
+    pub proxy: ProxySettings,
+    pub tls: TlsSettings,
+    pub timeouts: ConnectTimeouts,
+    /// The largest upgrade response that will be accepted from the server.
+    /// Default is 16384 bytes
+    pub max_header_size: usize,
     pub extra_headers: Vec<String>,
 }
 
+impl Default for ConnectSettings {
+    fn default() -> Self {
+        ConnectSettings {
+            max_websocket_frame: 1_000_000,
+            max_frame_size: 1_000_000,
+            max_fragment_size: 4096,
+            stream_messages: false,
+            deflate: None,
+            proxy: ProxySettings::from_env(),
+            tls: TlsSettings::default(),
+            timeouts: ConnectTimeouts::default(),
+            max_header_size: 16384,
+            extra_headers: vec![],
+        }
+    }
+}
+
+/// Deadlines for each phase of connecting. `None` waits forever.
+#[derive(Copy, Clone, Debug)]
+pub struct ConnectTimeouts {
+    pub dns: Option<Duration>,
+    /// Includes setting up the tunnel when connecting through a proxy
+    pub tcp: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// Sending the upgrade request and reading the response
+    pub upgrade: Option<Duration>,
+}
+
+impl Default for ConnectTimeouts {
+    fn default() -> Self {
+        ConnectTimeouts {
+            dns: Some(Duration::from_secs(10)),
+            tcp: Some(Duration::from_secs(10)),
+            tls: Some(Duration::from_secs(10)),
+            upgrade: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
 #[async]
 pub fn connect_with_settings(uri: Uri, settings: ConnectSettings) -> DResult<Client> {
-    let connection = await!(establish_connection(uri.clone()))?;
+    let proxy = settings.proxy.clone();
+    let tls = settings.tls.clone();
+    let timeouts = settings.timeouts;
+    let connection = await!(establish_connection(uri.clone(), proxy, tls, timeouts))?;
 
+    let upgrade = timeouts.upgrade;
     match connection {
-        Either::Left((r, w)) => await!(do_connect(uri, settings, r, w)),
-        Either::Right((r, w)) => await!(do_connect(uri, settings, r, w)),
+        Either::Left((r, w)) => {
+            let upgraded = do_connect(uri, settings, r, w);
+            await!(with_timeout(upgraded, upgrade, WError::UpgradeTimeout))
+        }
+        Either::Right((r, w)) => {
+            let upgraded = do_connect(uri, settings, r, w);
+            await!(with_timeout(upgraded, upgrade, WError::UpgradeTimeout))
+        }
     }
 }
 
+/// Runs the handshake and framing over a transport which is already
+/// connected, such as a Unix socket, an in-memory pipe or a tunnel. The
+/// stream is used as-is, so for `wss` uris it has to be encrypted already.
+#[async]
+pub fn connect_over<S>(stream: S, uri: Uri, settings: ConnectSettings) -> DResult<Client>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // The Host header comes from the authority
+    if uri.authority_part().is_none() {
+        return Err(WError::InvalidUri.into());
+    }
+
+    let (r, w) = stream.split();
+    let upgrade = settings.timeouts.upgrade;
+    let upgraded = do_connect(uri, settings, r, w);
+    await!(with_timeout(upgraded, upgrade, WError::UpgradeTimeout))
+}
+
 #[async]
 fn do_connect<R, W>(
     uri: Uri,
     settings: ConnectSettings,
-    mut reader: BufReader<R>,
+    reader: R,
     writer: W,
 ) -> DResult<Client>
 where
@@ -59,36 +153,226 @@ where
          Upgrade: WebSocket\r\n\
          Sec-WebSocket-Key: {key}\r\n\
          Sec-WebSocket-Version: 13\r\n\
+         {extensions}\
          {extra}\
          \r\n",
         path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"),
         host = host_port(&uri),
         key = Base64Display::standard(&sec_key),
+        extensions = DisplayExtensions(settings.deflate.as_ref()),
         extra = DisplayHeaders(&settings.extra_headers),
     );
-    let (mut writer, _) = await!(write_all(writer, headers))?;
+    let (writer, _) = await!(write_all(writer, headers))?;
 
-    let mut buf = Vec::with_capacity(512);
-    loop {
-        let prev_len = buf.len();
-        let (nreader, nbuf) = await!(read_until(reader, b'\n', buf))?;
-        buf = nbuf;
+    let (head, reader) = await!(read_head(reader, settings.max_header_size))?;
+    let (headers, deflate) = check_response(&head, &hashed_key, &settings)?;
+
+    let mut decoder = ClientDecoder::with_limit(settings.max_websocket_frame)
+        .with_frame_limit(settings.max_frame_size);
+    if settings.stream_messages {
+        decoder = decoder.streaming();
+    }
+    let mut encoder = ClientEncoder::with_fragment_size(settings.max_fragment_size);
+    if let Some(ref config) = deflate {
+        debug!("Negotiated permessage-deflate: {:?}", config);
+        decoder = decoder.with_deflate(config);
+        encoder = encoder.with_deflate(config);
+    }
+
+    let reader = FramedRead::new(reader, decoder);
+    let writer = FramedWrite::new(writer, encoder);
+
+    Ok(Client::from_halves(reader, writer, headers))
+}
+
+pub fn connect(uri: Uri) -> impl Future<Item = Client, Error = Error> {
+    connect_with_settings(uri, ConnectSettings::default())
+}
+
+pub fn connect_with_auth<S>(uri: Uri, header: S) -> impl Future<Item = Client, Error = Error>
+where
+    S: Into<String>,
+{
+    connect_with_settings(
+        uri,
+        ConnectSettings {
+            extra_headers: vec![header.into()],
+            ..Default::default()
+        },
+    )
+}
+
+#[async]
+fn establish_connection(
+    uri: Uri,
+    proxy: ProxySettings,
+    tls: TlsSettings,
+    timeouts: ConnectTimeouts,
+) -> DResult<Connection> {
+    let secure = match uri.scheme_part().map(Scheme::as_str) {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => return Err(WError::InvalidUri.into()),
+    };
+
+    if secure {
+        await!(establish_tls(uri, proxy, tls, timeouts))
+    } else {
+        await!(establish_plain(uri, proxy, timeouts))
+    }
+}
+
+#[async]
+fn establish_plain(
+    uri: Uri,
+    proxy: ProxySettings,
+    timeouts: ConnectTimeouts,
+) -> DResult<Connection> {
+    let stream = await!(establish_tcp(uri, proxy, timeouts, 80))?;
+    let (r, w) = stream.split();
+    Ok(Either::Left((r, w)))
+}
+
+#[async]
+fn establish_tls(
+    uri: Uri,
+    proxy: ProxySettings,
+    tls: TlsSettings,
+    timeouts: ConnectTimeouts,
+) -> DResult<Connection> {
+    let stream = await!(establish_tcp(uri.clone(), proxy, timeouts, 443))?;
+    let connector = tls.connector()?;
+    let server_name = tls
+        .server_name
+        .clone()
+        .unwrap_or_else(|| domain(&uri).to_string());
+    let handshake = connector
+        .connect_async(&server_name, stream)
+        .map_err(Error::from);
+    let tls_stream = await!(with_timeout(handshake, timeouts.tls, WError::TlsTimeout))?;
+    tls.check_pins(&tls_stream)?;
+    let (r, w) = tls_stream.split();
+    Ok(Either::Right((r, w)))
+}
+
+#[async]
+fn establish_tcp(
+    uri: Uri,
+    proxy: ProxySettings,
+    timeouts: ConnectTimeouts,
+    default_port: u16,
+) -> DResult<TcpStream> {
+    let authority = uri.authority_part().ok_or(WError::InvalidUri)?;
+    let host = authority.host().to_string();
+    let port = authority.port().unwrap_or(default_port);
+
+    if let Some(proxy) = proxy.for_host(&host).cloned() {
+        let tunnel = proxy::tunnel(proxy, host, port);
+        return await!(with_timeout(tunnel, timeouts.tcp, WError::ConnectTimeout));
+    }
+
+    let resolve = net::resolve(&host, port);
+    let addrs = await!(with_timeout(resolve, timeouts.dns, WError::DnsTimeout))?;
+    if addrs.is_empty() {
+        return Err(WError::ServerNotFound.into());
+    }
+
+    let connect = net::connect_any(addrs);
+    await!(with_timeout(connect, timeouts.tcp, WError::ConnectTimeout))
+}
+
+/// Fails with `error` if `future` takes longer than `timeout`
+fn with_timeout<F>(
+    future: F,
+    timeout: Option<Duration>,
+    error: WError,
+) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return FutureEither::A(future),
+    };
+
+    let deadline = Deadline::new(future, Instant::now() + timeout);
+    FutureEither::B(deadline.map_err(move |e| {
+        if e.is_elapsed() {
+            error.into()
+        } else if e.is_timer() {
+            e.into_timer().map(Error::from).unwrap_or(Error::FutureError)
+        } else {
+            e.into_inner().unwrap_or(Error::FutureError)
+        }
+    }))
+}
+
+/// Reads an HTTP head of at most `max_size` bytes. It may arrive together
+/// with the first frames, so the stream is read in chunks and whatever
+/// follows the head is kept in the returned reader for the decoder.
+#[async]
+pub fn read_head<R>(mut reader: R, max_size: usize) -> DResult<(Vec<u8>, Leftover<R>)>
+where
+    R: AsyncRead + Send + 'static,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = vec![0; 1024];
+    let head_len = loop {
+        let (nreader, nchunk, read) = await!(read(reader, chunk))?;
         reader = nreader;
+        chunk = nchunk;
+        if read == 0 {
+            return Err(WError::IncompleteHeaders.into());
+        }
 
-        let read = buf.len() - prev_len;
-        if read <= 2 {
-            break;
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..read]);
+        if let Some(end) = find_head_end(&buf[searched..]) {
+            break searched + end;
+        }
+        if buf.len() > max_size {
+            return Err(WError::HeadersTooLarge(buf.len()).into());
         }
+    };
+    if head_len > max_size {
+        return Err(WError::HeadersTooLarge(head_len).into());
     }
 
-    let mut headers = [EMPTY_HEADER; 20];
-    let mut resp = Response::new(&mut headers);
-    match resp.parse(&buf) {
-        Ok(Status::Complete(_)) => (),
-        Ok(Status::Partial) => return Err(WError::IncompleteHeaders.into()),
-        Err(e) => return Err(WError::Headers(e).into()),
+    let leftover = buf.split_off(head_len);
+    let reader = Leftover {
+        buf: leftover,
+        pos: 0,
+        inner: reader,
     };
+    Ok((buf, reader))
+}
 
+/// Validates the server's upgrade response. Returns its headers and the
+/// negotiated permessage-deflate parameters.
+fn check_response(
+    head: &[u8],
+    hashed_key: &[u8; 20],
+    settings: &ConnectSettings,
+) -> DResult<(HeaderMap, Option<DeflateConfig>)> {
+    // Start with room for plenty of headers, and grow until they all fit
+    let mut capacity = 32;
+    loop {
+        let mut headers = vec![EMPTY_HEADER; capacity];
+        let mut resp = Response::new(&mut headers);
+        match resp.parse(head) {
+            Ok(Status::Complete(_)) => return check_upgrade(&resp, hashed_key, settings),
+            Ok(Status::Partial) => return Err(WError::IncompleteHeaders.into()),
+            Err(HttpError::TooManyHeaders) => capacity *= 2,
+            Err(e) => return Err(WError::Headers(e).into()),
+        }
+    }
+}
+
+fn check_upgrade(
+    resp: &Response,
+    hashed_key: &[u8; 20],
+    settings: &ConnectSettings,
+) -> DResult<(HeaderMap, Option<DeflateConfig>)> {
     // RFC 6455 Page 19, Point 1
     if resp.code != Some(101) {
         return Err(WError::InvalidResponseCode(resp.code).into());
@@ -97,17 +381,18 @@ where
     let mut has_upgrade = false;
     let mut has_connection = false;
     let mut sec_accept = None;
-    let mut sec_extensions = false;
+    let mut sec_extensions = None;
     let mut sec_protocols = false;
 
-    for header in resp.headers {
+    for header in resp.headers.iter() {
         if header.name.eq_ignore_ascii_case("Upgrade") {
             if header.value.eq_ignore_ascii_case(b"websocket") {
                 has_upgrade = true;
             }
         } else if header.name.eq_ignore_ascii_case("Connection") {
-            for tok in header.value.split(|&b| b == b' ') {
-                if tok.eq_ignore_ascii_case(b"upgrade") {
+            for tok in header.value.split(|&b| b == b',') {
+                let tok = str::from_utf8(tok).map(str::trim).unwrap_or("");
+                if tok.eq_ignore_ascii_case("upgrade") {
                     has_connection = true;
                 }
             }
@@ -115,7 +400,11 @@ where
             sec_accept = str::from_utf8(header.value).map(str::trim).ok();
         } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
             if !header.value.iter().all(|b| b.is_ascii_whitespace()) {
-                sec_extensions = true;
+                // Only one extension is ever offered
+                if sec_extensions.is_some() {
+                    return Err(WError::UnexpectedExtensions.into());
+                }
+                sec_extensions = Some(header.value);
             }
         } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
             if !header.value.iter().all(|b| b.is_ascii_whitespace()) {
@@ -131,82 +420,46 @@ where
 
     // RFC 6455 Page 19, Point 4
     if !sec_accept
-        .map(|a| b64_equal(a, &hashed_key))
+        .map(|a| b64_equal(a, hashed_key))
         .unwrap_or(false)
     {
         return Err(WError::BadSecretKey.into());
     }
 
-    // RFC6455 Page 19, Point 5/6. I'm not using any extensions/protocols,
-    // and the server isn't allowed to turn on ones I didn't request.
-    if sec_extensions || sec_protocols {
+    // RFC6455 Page 19, Point 5/6. The only extension I offer is
+    // permessage-deflate, and the server isn't allowed to turn on ones I
+    // didn't request.
+    let deflate = match (sec_extensions, settings.deflate.as_ref()) {
+        (None, _) => None,
+        (Some(response), Some(offer)) => Some(offer.negotiate(response)?),
+        (Some(_), None) => return Err(WError::UnexpectedExtensions.into()),
+    };
+    if sec_protocols {
         return Err(WError::UnexpectedExtensions.into());
     }
 
-    let decoder = ClientDecoder::with_limit(settings.max_websocket_frame);
-    let reader = FramedRead::new(reader, decoder);
-    let writer = FramedWrite::new(writer, ClientEncoder);
-
-    let reader = Box::new(reader);
-    let writer = Box::new(writer);
-
-    Ok(Client { reader, writer })
+    Ok((header_map(resp.headers), deflate))
 }
 
-pub fn connect(uri: Uri) -> impl Future<Item = Client, Error = Error> {
-    connect_with_settings(
-        uri,
-        ConnectSettings {
-            max_websocket_frame: 1_000_000,
-            extra_headers: vec![],
-        },
-    )
-}
-
-pub fn connect_with_auth<S>(uri: Uri, header: S) -> impl Future<Item = Client, Error = Error>
-where
-    S: Into<String>,
-{
-    connect_with_settings(
-        uri,
-        ConnectSettings {
-            max_websocket_frame: 1_000_000,
-            extra_headers: vec![header.into()],
-        },
-    )
-}
-
-#[async]
-fn establish_connection(uri: Uri) -> DResult<Connection> {
-    let secure = match uri.scheme_part().map(Scheme::as_str) {
-        Some("ws") => false,
-        Some("wss") => true,
-        _ => return Err(WError::InvalidUri.into()),
-    };
-
-    if secure {
-        await!(establish_tls(uri))
-    } else {
-        await!(establish_plain(uri))
+/// Copies parsed headers into a `HeaderMap`, skipping any with invalid names
+/// or values
+pub fn header_map(parsed: &[Header]) -> HeaderMap {
+    let mut headers = HeaderMap::with_capacity(parsed.len());
+    for header in parsed {
+        let name = HeaderName::from_bytes(header.name.as_bytes());
+        let value = HeaderValue::from_bytes(header.value);
+        if let (Ok(name), Ok(value)) = (name, value) {
+            headers.append(name, value);
+        }
     }
+    headers
 }
 
-#[async]
-fn establish_plain(uri: Uri) -> DResult<Connection> {
-    let addr = get_addr(&uri, 80)?;
-    let stream = await!(TcpStream::connect(&addr))?;
-    let (r, w) = stream.split();
-    Ok(Either::Left((BufReader::new(r), w)))
-}
-
-#[async]
-fn establish_tls(uri: Uri) -> DResult<Connection> {
-    let addr = get_addr(&uri, 443)?;
-    let stream = await!(TcpStream::connect(&addr))?;
-    let tls = TlsConnector::builder()?.build()?;
-    let tls_stream = await!(tls.connect_async(domain(&uri), stream))?;
-    let (r, w) = tls_stream.split();
-    Ok(Either::Right((BufReader::new(r), w)))
+/// Position just past the blank line which ends the headers
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4)
 }
 
 fn b64_equal(s: &str, b: &[u8]) -> bool {
@@ -229,17 +482,8 @@ fn host_port(uri: &Uri) -> &str {
     }
 }
 
-fn get_addr(uri: &Uri, default_port: u16) -> DResult<SocketAddr> {
-    let authority = uri.authority_part().ok_or(WError::InvalidUri)?;
-    let addr = (authority.host(), authority.port().unwrap_or(default_port))
-        .to_socket_addrs()?
-        .nth(0)
-        .ok_or(WError::ServerNotFound)?;
-
-    Ok(addr)
-}
-
-fn hash_sec_key(key: &[u8; 16]) -> [u8; 20] {
+/// The `Sec-WebSocket-Accept` value a server answers `key` with
+pub fn hash_sec_key(key: &[u8; 16]) -> [u8; 20] {
     let mut key_hasher = Sha1::new();
     write!(
         Sha1Write(&mut key_hasher),
@@ -249,13 +493,35 @@ fn hash_sec_key(key: &[u8; 16]) -> [u8; 20] {
     key_hasher.digest().bytes()
 }
 
-type PlainRead = BufReader<ReadHalf<TcpStream>>;
-type TlsRead = BufReader<ReadHalf<TlsStream<TcpStream>>>;
+type PlainRead = ReadHalf<TcpStream>;
+type TlsRead = ReadHalf<TlsStream<TcpStream>>;
 type PlainWrite = WriteHalf<TcpStream>;
 type TlsWrite = WriteHalf<TlsStream<TcpStream>>;
 
 type Connection = Either<(PlainRead, PlainWrite), (TlsRead, TlsWrite)>;
 
+/// Bytes read along with an HTTP head, returned before reading more from the
+/// stream
+pub struct Leftover<R> {
+    buf: Vec<u8>,
+    pos: usize,
+    inner: R,
+}
+
+impl<R: Read> Read for Leftover<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buf.len() {
+            let len = cmp::min(dst.len(), self.buf.len() - self.pos);
+            dst[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+        self.inner.read(dst)
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Leftover<R> {}
+
 struct DisplayHeaders<'a>(&'a [String]);
 impl<'a> fmt::Display for DisplayHeaders<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -267,6 +533,16 @@ impl<'a> fmt::Display for DisplayHeaders<'a> {
     }
 }
 
+struct DisplayExtensions<'a>(Option<&'a DeflateSettings>);
+impl<'a> fmt::Display for DisplayExtensions<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(deflate) = self.0 {
+            write!(fmt, "Sec-WebSocket-Extensions: {}\r\n", deflate.offer())?;
+        }
+        Ok(())
+    }
+}
+
 struct Sha1Write<'a>(&'a mut Sha1);
 impl<'a> fmt::Write for Sha1Write<'a> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
@@ -289,3 +565,36 @@ impl<'a> fmt::Write for B64Eq<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_response, hash_sec_key, ConnectSettings};
+
+    /// A 101 for the sample key of RFC 6455 Section 1.3
+    fn response(connection: &str) -> String {
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: {}\r\n\
+             Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+            connection
+        )
+    }
+
+    #[test]
+    fn connection_token_lists() {
+        let hashed_key = hash_sec_key(b"the sample nonce");
+        let settings = ConnectSettings::default();
+
+        for &connection in &["Upgrade", "upgrade", "keep-alive, Upgrade", "Upgrade,keep-alive"] {
+            let head = response(connection);
+            let result = check_response(head.as_bytes(), &hashed_key, &settings);
+            assert!(result.is_ok(), "{}", connection);
+        }
+        for &connection in &["keep-alive", "Upgrade keep-alive", "upgraded"] {
+            let head = response(connection);
+            let result = check_response(head.as_bytes(), &hashed_key, &settings);
+            assert!(result.is_err(), "{}", connection);
+        }
+    }
+}
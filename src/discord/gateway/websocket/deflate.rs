@@ -0,0 +1,530 @@
+use discord::gateway::websocket::Error;
+
+use std::str;
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// Every message compressed with permessage-deflate ends in an empty stored
+/// block, which is left out on the wire (RFC 7692 Section 7.2.1)
+const DEFLATE_TAIL: [u8; 4] = [0, 0, 255, 255];
+
+/// What to ask for when offering permessage-deflate to the server
+#[derive(Clone, Debug, Default)]
+pub struct DeflateSettings {
+    /// Start every outgoing message with a fresh compression context. Uses
+    /// less memory at the cost of a worse ratio.
+    pub client_no_context_takeover: bool,
+    /// Ask the server to start every message with a fresh context
+    pub server_no_context_takeover: bool,
+    /// Ask the server to use a window of at most 2^n bytes, between 8 and 15
+    pub server_max_window_bits: Option<u8>,
+}
+
+/// The parameters the server agreed to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DeflateConfig {
+    pub client_no_context_takeover: bool,
+    pub server_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+}
+
+impl DeflateSettings {
+    /// The value of the `Sec-WebSocket-Extensions` request header
+    pub fn offer(&self) -> String {
+        let mut offer = String::from("permessage-deflate");
+        if self.client_no_context_takeover {
+            offer.push_str("; client_no_context_takeover");
+        }
+        if self.server_no_context_takeover {
+            offer.push_str("; server_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            offer.push_str(&format!("; server_max_window_bits={}", bits));
+        }
+        offer
+    }
+
+    /// Checks the server's `Sec-WebSocket-Extensions` response against what
+    /// was offered
+    pub fn negotiate(&self, response: &[u8]) -> Result<DeflateConfig, Error> {
+        let response = str::from_utf8(response).map_err(|_| Error::BadDeflateParams)?;
+
+        // Only one extension was offered, so only one may be accepted
+        if response.contains(',') {
+            return Err(Error::UnexpectedExtensions);
+        }
+
+        let mut params = response.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") {
+            return Err(Error::UnexpectedExtensions);
+        }
+
+        let mut config = DeflateConfig {
+            client_no_context_takeover: self.client_no_context_takeover,
+            server_no_context_takeover: false,
+            server_max_window_bits: 15,
+        };
+        let mut window_bits = None;
+        let mut seen = Vec::new();
+
+        for param in params {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts.next().map(|v| v.trim().trim_matches('"'));
+
+            if seen.contains(&name) {
+                return Err(Error::BadDeflateParams);
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("client_no_context_takeover", None) => config.client_no_context_takeover = true,
+                ("server_no_context_takeover", None) => config.server_no_context_takeover = true,
+                ("server_max_window_bits", Some(value)) => {
+                    let bits = parse_window_bits(value)?;
+                    if bits > self.server_max_window_bits.unwrap_or(15) {
+                        return Err(Error::BadDeflateParams);
+                    }
+                    window_bits = Some(bits);
+                }
+                // The compressor can't be limited to a smaller window, so
+                // client_max_window_bits is never offered and the server
+                // isn't allowed to send it back
+                _ => return Err(Error::BadDeflateParams),
+            }
+        }
+
+        // The server has to either decline the offer or honour these
+        if self.server_no_context_takeover && !config.server_no_context_takeover {
+            return Err(Error::BadDeflateParams);
+        }
+        if self.server_max_window_bits.is_some() && window_bits.is_none() {
+            return Err(Error::BadDeflateParams);
+        }
+
+        config.server_max_window_bits = window_bits.unwrap_or(15);
+        Ok(config)
+    }
+}
+
+impl DeflateConfig {
+    /// Picks the first permessage-deflate offer in a client's
+    /// `Sec-WebSocket-Extensions` header which can be honoured. Offers that
+    /// limit the server's window are passed over, since the compressor
+    /// always uses a full one.
+    pub fn accept(offers: &[u8]) -> Option<DeflateConfig> {
+        let offers = str::from_utf8(offers).ok()?;
+        offers.split(',').filter_map(accept_offer).next()
+    }
+
+    /// The value of the `Sec-WebSocket-Extensions` response header
+    pub fn response(&self) -> String {
+        let mut response = String::from("permessage-deflate");
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        response
+    }
+
+    /// The same agreement from the other end. `Deflater` and `Inflater`
+    /// take the client's view, so a server hands them this.
+    pub fn reversed(&self) -> DeflateConfig {
+        DeflateConfig {
+            client_no_context_takeover: self.server_no_context_takeover,
+            server_no_context_takeover: self.client_no_context_takeover,
+            server_max_window_bits: self.server_max_window_bits,
+        }
+    }
+}
+
+fn accept_offer(offer: &str) -> Option<DeflateConfig> {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next() != Some("permessage-deflate") {
+        return None;
+    }
+
+    let mut config = DeflateConfig {
+        client_no_context_takeover: false,
+        server_no_context_takeover: false,
+        server_max_window_bits: 15,
+    };
+    for param in params {
+        let mut parts = param.splitn(2, '=');
+        match (parts.next().unwrap_or("").trim(), parts.next()) {
+            ("client_no_context_takeover", None) => config.client_no_context_takeover = true,
+            ("server_no_context_takeover", None) => config.server_no_context_takeover = true,
+            // Only a hint that the client could handle a limit, which the
+            // response is free to leave out
+            ("client_max_window_bits", _) => (),
+            _ => return None,
+        }
+    }
+    Some(config)
+}
+
+fn parse_window_bits(value: &str) -> Result<u8, Error> {
+    match value.parse::<u8>() {
+        Ok(bits @ 8..=15) => Ok(bits),
+        _ => Err(Error::BadDeflateParams),
+    }
+}
+
+/// Compresses outgoing messages
+pub struct Deflater {
+    zlib: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(config: &DeflateConfig) -> Deflater {
+        Deflater {
+            zlib: Compress::new(Compression::default(), false),
+            no_context_takeover: config.client_no_context_takeover,
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.zlib.total_in();
+
+        loop {
+            if out.capacity() - out.len() < 64 {
+                let more = out.capacity().max(64);
+                out.reserve(more);
+            }
+
+            let consumed = (self.zlib.total_in() - start) as usize;
+            self.zlib
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|_| Error::BadCompression)?;
+
+            // A sync flush is done once all input is in and there was room left over
+            let consumed = (self.zlib.total_in() - start) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&DEFLATE_TAIL) {
+            let len = out.len() - DEFLATE_TAIL.len();
+            out.truncate(len);
+        }
+        if self.no_context_takeover {
+            self.zlib.reset();
+        }
+        Ok(out)
+    }
+}
+
+/// Decompresses incoming messages
+pub struct Inflater {
+    zlib: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(config: &DeflateConfig) -> Inflater {
+        // A full size window can inflate anything compressed with a smaller one
+        Inflater {
+            zlib: Decompress::new(false),
+            no_context_takeover: config.server_no_context_takeover,
+        }
+    }
+
+    /// Decompresses a whole message, failing once it grows past `limit` bytes
+    pub fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+        self.decompress_part(data, true, limit)
+    }
+
+    /// Decompresses one frame of a message, failing once it grows past
+    /// `limit` bytes. `last` says whether the frame ends the message.
+    pub fn decompress_part(
+        &mut self,
+        data: &[u8],
+        last: bool,
+        limit: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut input = Vec::with_capacity(data.len() + DEFLATE_TAIL.len());
+        input.extend_from_slice(data);
+        if last {
+            input.extend_from_slice(&DEFLATE_TAIL);
+        }
+
+        let mut out = Vec::with_capacity(data.len() * 2 + 64);
+        let start_in = self.zlib.total_in();
+
+        loop {
+            if out.capacity() - out.len() < 64 {
+                let more = out.capacity().max(64);
+                out.reserve(more);
+            }
+
+            let before_in = self.zlib.total_in();
+            let before_out = out.len();
+            let consumed = (before_in - start_in) as usize;
+            let status = self
+                .zlib
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| Error::BadCompression)?;
+
+            if out.len() > limit {
+                return Err(Error::FrameTooLarge(out.len()));
+            }
+
+            let consumed = (self.zlib.total_in() - start_in) as usize;
+            let finished = consumed == input.len() && out.len() < out.capacity();
+            if finished || status == Status::StreamEnd {
+                break;
+            }
+
+            let progress = self.zlib.total_in() != before_in || out.len() != before_out;
+            if !progress && out.len() < out.capacity() {
+                // Truncated input which no amount of output space will finish
+                return Err(Error::BadCompression);
+            }
+        }
+
+        if last && self.no_context_takeover {
+            self.zlib.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeflateConfig, DeflateSettings, Deflater, Inflater, DEFLATE_TAIL};
+    use discord::gateway::websocket::Error;
+
+    fn config(client_no_context_takeover: bool, server_no_context_takeover: bool) -> DeflateConfig {
+        DeflateConfig {
+            client_no_context_takeover,
+            server_no_context_takeover,
+            server_max_window_bits: 15,
+        }
+    }
+
+    fn window_bits(bits: u8) -> DeflateSettings {
+        DeflateSettings {
+            server_max_window_bits: Some(bits),
+            ..Default::default()
+        }
+    }
+
+    fn bad_params(settings: &DeflateSettings, response: &str) {
+        match settings.negotiate(response.as_bytes()) {
+            Err(Error::BadDeflateParams) => (),
+            other => panic!("{}: {:?}", response, other),
+        }
+    }
+
+    #[test]
+    fn negotiate_defaults() {
+        let offer = DeflateSettings::default();
+        assert_eq!(offer.offer(), "permessage-deflate");
+        assert_eq!(
+            offer.negotiate(b"permessage-deflate").unwrap(),
+            config(false, false)
+        );
+
+        // The server may turn off context takeover for either side unasked
+        let response = b"permessage-deflate; client_no_context_takeover";
+        assert_eq!(offer.negotiate(response).unwrap(), config(true, false));
+        let response = b"permessage-deflate;server_no_context_takeover";
+        assert_eq!(offer.negotiate(response).unwrap(), config(false, true));
+
+        let offer = DeflateSettings {
+            client_no_context_takeover: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            offer.negotiate(b"permessage-deflate").unwrap(),
+            config(true, false)
+        );
+    }
+
+    #[test]
+    fn negotiate_server_context_takeover() {
+        let offer = DeflateSettings {
+            server_no_context_takeover: true,
+            ..Default::default()
+        };
+        let response = b"permessage-deflate; server_no_context_takeover";
+        assert_eq!(offer.negotiate(response).unwrap(), config(false, true));
+        bad_params(&offer, "permessage-deflate");
+    }
+
+    #[test]
+    fn negotiate_server_max_window_bits() {
+        let offer = window_bits(10);
+        assert_eq!(
+            offer.offer(),
+            "permessage-deflate; server_max_window_bits=10"
+        );
+
+        for &(response, bits) in &[
+            ("permessage-deflate; server_max_window_bits=10", 10),
+            ("permessage-deflate; server_max_window_bits=8", 8),
+            ("permessage-deflate; server_max_window_bits=\"9\"", 9),
+        ] {
+            let config = offer.negotiate(response.as_bytes()).unwrap();
+            assert_eq!(config.server_max_window_bits, bits);
+        }
+
+        // Larger than asked for, out of range, or missing
+        bad_params(&offer, "permessage-deflate; server_max_window_bits=11");
+        bad_params(&offer, "permessage-deflate; server_max_window_bits=7");
+        bad_params(&offer, "permessage-deflate; server_max_window_bits=ten");
+        bad_params(&offer, "permessage-deflate; server_max_window_bits");
+        bad_params(&offer, "permessage-deflate");
+
+        // Unasked, the server may still limit its window
+        let offer = DeflateSettings::default();
+        let response = b"permessage-deflate; server_max_window_bits=12";
+        assert_eq!(
+            offer.negotiate(response).unwrap().server_max_window_bits,
+            12
+        );
+        bad_params(&offer, "permessage-deflate; server_max_window_bits=16");
+    }
+
+    #[test]
+    fn negotiate_bad_params() {
+        let offer = DeflateSettings::default();
+        bad_params(&offer, "permessage-deflate; client_max_window_bits=10");
+        bad_params(&offer, "permessage-deflate; x-unknown");
+        bad_params(&offer, "permessage-deflate; client_no_context_takeover=1");
+        bad_params(
+            &offer,
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+        );
+        bad_params(
+            &window_bits(12),
+            "permessage-deflate; server_max_window_bits=10; server_max_window_bits=12",
+        );
+        match offer.negotiate(b"permessage-deflate; \xff") {
+            Err(Error::BadDeflateParams) => (),
+            other => panic!("{:?}", other),
+        }
+
+        for response in &[
+            "x-webkit-deflate-frame",
+            "permessage-deflate, permessage-deflate",
+        ] {
+            match offer.negotiate(response.as_bytes()) {
+                Err(Error::UnexpectedExtensions) => (),
+                other => panic!("{}: {:?}", response, other),
+            }
+        }
+    }
+
+    #[test]
+    fn accept_offers() {
+        assert_eq!(
+            DeflateConfig::accept(b"permessage-deflate; client_max_window_bits"),
+            Some(config(false, false))
+        );
+
+        // The first offer that can be honoured wins
+        let offers = b"x-webkit-deflate-frame, \
+                       permessage-deflate; server_max_window_bits=10, \
+                       permessage-deflate; server_no_context_takeover, \
+                       permessage-deflate";
+        let accepted = DeflateConfig::accept(offers).unwrap();
+        assert_eq!(accepted, config(false, true));
+        assert_eq!(
+            accepted.response(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+        assert_eq!(accepted.reversed(), config(true, false));
+
+        assert_eq!(
+            DeflateConfig::accept(b"permessage-deflate; x-unknown"),
+            None
+        );
+        assert_eq!(DeflateConfig::accept(b"x-webkit-deflate-frame"), None);
+    }
+
+    #[test]
+    fn tail_is_stripped() {
+        // RFC 7692 Section 7.2.3.2, "Hello" twice sharing a window
+        let mut inflater = Inflater::new(&config(false, false));
+        let first = inflater.decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 100);
+        assert_eq!(first.unwrap(), b"Hello");
+        let second = inflater.decompress(&[0xf2, 0x00, 0x11, 0x00, 0x00], 100);
+        assert_eq!(second.unwrap(), b"Hello");
+
+        let text = "All work and no play makes Jack a dull boy. "
+            .repeat(20)
+            .into_bytes();
+        for &no_context_takeover in &[false, true] {
+            let config = config(no_context_takeover, no_context_takeover);
+            let mut deflater = Deflater::new(&config);
+            let mut inflater = Inflater::new(&config);
+
+            let first = deflater.compress(&text).unwrap();
+            let second = deflater.compress(&text).unwrap();
+            for compressed in &[&first, &second] {
+                assert!(!compressed.ends_with(&DEFLATE_TAIL));
+                assert_eq!(inflater.decompress(compressed, text.len()).unwrap(), text);
+            }
+
+            // A fresh context has to spell the message out again
+            if no_context_takeover {
+                assert_eq!(first, second);
+            } else {
+                assert!(second.len() < first.len());
+            }
+        }
+    }
+
+    #[test]
+    fn frames_of_a_message() {
+        let text = "All work and no play makes Jack a dull boy. "
+            .repeat(20)
+            .into_bytes();
+        let compressed = Deflater::new(&config(false, false))
+            .compress(&text)
+            .unwrap();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+
+        let mut inflater = Inflater::new(&config(false, false));
+        let mut inflated = inflater.decompress_part(first, false, text.len()).unwrap();
+        inflated.extend(inflater.decompress_part(second, true, text.len()).unwrap());
+        assert_eq!(inflated, text);
+    }
+
+    #[test]
+    fn inflate_limit() {
+        let zeros = vec![0; 100_000];
+        let compressed = Deflater::new(&config(false, false))
+            .compress(&zeros)
+            .unwrap();
+        assert!(compressed.len() < 1000);
+
+        let mut inflater = Inflater::new(&config(false, false));
+        match inflater.decompress(&compressed, 10_000) {
+            Err(Error::FrameTooLarge(len)) => assert!(len > 10_000),
+            other => panic!("{:?}", other.map(|out| out.len())),
+        }
+
+        let mut inflater = Inflater::new(&config(false, false));
+        assert_eq!(
+            inflater.decompress(&compressed, zeros.len()).unwrap(),
+            zeros
+        );
+    }
+
+    #[test]
+    fn corrupt_input() {
+        // A block of the reserved type 3
+        let mut inflater = Inflater::new(&config(false, false));
+        match inflater.decompress(&[0xff; 16], 1000) {
+            Err(Error::BadCompression) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
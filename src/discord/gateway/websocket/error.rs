@@ -1,4 +1,5 @@
-use discord::gateway::websocket::OpCode;
+use discord::gateway::websocket::message::MAX_CLOSE_REASON;
+use discord::gateway::websocket::{CloseCode, OpCode};
 
 use std::fmt;
 use std::io;
@@ -17,12 +18,58 @@ pub enum Error {
     BadSecretKey,
     UnexpectedExtensions,
     BadCompression,
+    BadCloseFrame,
+    BadDeflateParams,
+    UnexpectedCompression,
+    UnmaskedFrame,
+    MaskedFrame,
+    ReservedBitsSet,
+    ControlFrameTooLarge,
+    InvalidRequest,
+    UnsendableMessage,
+    UnexpectedStream,
+    BadCertificate,
+    InvalidPem,
+    PinMismatch,
+    DnsTimeout,
+    ConnectTimeout,
+    TlsTimeout,
+    UpgradeTimeout,
 
     InvalidResponseCode(Option<u16>),
     FrameTooLarge(usize),
+    OversizedFrame(u64),
+    InvalidFrameLength(u64),
+    HeadersTooLarge(usize),
+    CloseReasonTooLong(usize),
+    UnsendableCloseCode(CloseCode),
+    InvalidCloseCode(u16),
     UnexpectedFrame(OpCode, &'static [OpCode]),
 }
 
+impl Error {
+    /// The code to fail the connection with when reading fails with this
+    /// error, if the other side broke RFC 6455 or sent more than we accept
+    /// (Sections 7.1.7 and 7.4.1)
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            Error::NonFinalControlFrame
+            | Error::BadCloseFrame
+            | Error::UnexpectedCompression
+            | Error::UnmaskedFrame
+            | Error::MaskedFrame
+            | Error::ReservedBitsSet
+            | Error::ControlFrameTooLarge
+            | Error::InvalidFrameLength(_)
+            | Error::InvalidCloseCode(_)
+            | Error::UnexpectedFrame(..) => Some(CloseCode::ProtocolError),
+            Error::TextFrameNotUtf8 | Error::BadCompression => Some(CloseCode::InvalidPayload),
+            Error::OversizedFrame(_) | Error::FrameTooLarge(_) => Some(CloseCode::MessageTooBig),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -39,11 +86,64 @@ impl fmt::Display for Error {
                 fmt.write_str("Server enabled unexpected extensions/protocols")
             }
             Error::BadCompression => fmt.write_str("Failed to decompress a compressed payload"),
+            Error::BadDeflateParams => {
+                fmt.write_str("Server sent invalid permessage-deflate parameters")
+            }
+            Error::UnexpectedCompression => {
+                fmt.write_str("Server sent a compressed frame where none was allowed")
+            }
+            Error::UnmaskedFrame => fmt.write_str("Client sent an unmasked frame"),
+            Error::MaskedFrame => fmt.write_str("Server sent a masked frame"),
+            Error::ReservedBitsSet => fmt.write_str("Received a frame with RSV2 or RSV3 set"),
+            Error::ControlFrameTooLarge => {
+                fmt.write_str("Received a control frame with over 125 bytes of payload")
+            }
+            Error::InvalidRequest => fmt.write_str("Client sent an invalid upgrade request"),
+            Error::UnsendableMessage => fmt.write_str("Parts of streamed messages can't be sent"),
+            Error::UnexpectedStream => {
+                fmt.write_str("Received part of an unexpected streamed message")
+            }
+            Error::BadCertificate => {
+                fmt.write_str("Server sent a certificate that couldn't be parsed")
+            }
+            Error::InvalidPem => fmt.write_str("A root certificate is neither DER nor valid PEM"),
+            Error::PinMismatch => fmt.write_str("Server certificate matched none of the SPKI pins"),
+            Error::DnsTimeout => fmt.write_str("Timed out resolving the server's address"),
+            Error::ConnectTimeout => fmt.write_str("Timed out connecting to the server"),
+            Error::TlsTimeout => fmt.write_str("Timed out during the TLS handshake"),
+            Error::UpgradeTimeout => fmt.write_str("Timed out waiting for the upgrade response"),
+            Error::BadCloseFrame => {
+                fmt.write_str("Received a Close frame with a truncated status code")
+            }
             Error::InvalidResponseCode(Some(code)) => {
                 write!(fmt, "Server replied with unexpected HTTP {:03}", code)
             }
             Error::InvalidResponseCode(None) => write!(fmt, "Server replied with no HTTP code"),
             Error::FrameTooLarge(size) => write!(fmt, "Server sent {} byte frame", size),
+            Error::OversizedFrame(len) => write!(
+                fmt,
+                "Received a frame with a {} byte payload, over the frame size limit",
+                len
+            ),
+            Error::InvalidFrameLength(len) => write!(
+                fmt,
+                "Received a frame length of {:#x}, which has its most significant bit set",
+                len
+            ),
+            Error::HeadersTooLarge(size) => {
+                write!(fmt, "Server sent over {} bytes of HTTP headers", size)
+            }
+            Error::CloseReasonTooLong(len) => write!(
+                fmt,
+                "Close reason is {} bytes, at most {} are allowed",
+                len, MAX_CLOSE_REASON
+            ),
+            Error::UnsendableCloseCode(code) => {
+                write!(fmt, "Close code {} can't be sent in a Close frame", code)
+            }
+            Error::InvalidCloseCode(code) => {
+                write!(fmt, "Received a Close frame with invalid code {}", code)
+            }
             Error::UnexpectedFrame(sent, expected) => write!(
                 fmt,
                 "Server sent a {:?} frame when only 1 of {:?} was expected",
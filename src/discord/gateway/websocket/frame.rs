@@ -1,24 +1,25 @@
+use std::cmp;
 use std::mem;
 use std::u16;
 
-use byteorder::{BigEndian, ByteOrder};
-use bytes::{BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder, NativeEndian};
+use bytes::{BufMut, Bytes, BytesMut};
 
 pub struct Frame {
     pub flags: FrameFlags,
-    pub masked: bool,
+    /// Key the payload is masked with on the wire. The payload itself is
+    /// always kept unmasked.
     pub masking_key: Option<[u8; 4]>,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 impl Frame {
     pub fn new<P>(opcode: OpCode, payload: P) -> Self
     where
-        P: Into<Vec<u8>>,
+        P: Into<Bytes>,
     {
         Frame {
             flags: FrameFlags(FINAL_MASK | (opcode as u8 & OPCODE_MASK)),
-            masked: false,
             masking_key: None,
             payload: payload.into(),
         }
@@ -30,9 +31,8 @@ impl Frame {
     }
 
     pub fn with_mask(mut self, key: [u8; 4]) -> Frame {
-        self = self.unmask();
         self.masking_key = Some(key);
-        self.toggle_mask()
+        self
     }
 
     pub fn frame_size(&self) -> usize {
@@ -47,32 +47,20 @@ impl Frame {
             size += 2;
         }
 
+        if self.masking_key.is_some() {
+            size += 4;
+        }
+
         // payload
         size += self.payload.len();
 
         size
     }
 
-    pub fn mask(self) -> Self {
-        if self.masked {
-            self
-        } else {
-            self.toggle_mask()
-        }
-    }
-
-    pub fn unmask(self) -> Self {
-        if self.masked {
-            self.toggle_mask()
-        } else {
-            self
-        }
-    }
-
     pub fn encode(&self, dst: &mut BytesMut) {
         dst.put_u8(self.flags.0);
 
-        let mask_flag = if self.masked { 0b1000_0000 } else { 0 };
+        let mask_flag = if self.masking_key.is_some() { 0b1000_0000 } else { 0 };
         if self.payload.len() > u16::MAX as usize {
             dst.put_u8(127 | mask_flag);
             dst.put_u64_be(self.payload.len() as u64);
@@ -83,14 +71,21 @@ impl Frame {
             dst.put_u8(self.payload.len() as u8 | mask_flag);
         }
 
-        if let Some(key) = self.masking_key {
-            dst.put(&key[..]);
+        match self.masking_key {
+            Some(key) => {
+                dst.put(&key[..]);
+                let start = dst.len();
+                dst.put(&self.payload[..]);
+                apply_mask(&mut dst[start..], key);
+            }
+            None => dst.put(&self.payload[..]),
         }
-
-        dst.put(&self.payload);
     }
 
-    pub fn is_complete(data: &BytesMut) -> Option<(FrameFlags, usize)> {
+    /// The header length, masking key included, and the payload length of
+    /// the frame at the start of `data`, once enough of it has arrived. The
+    /// payload length is what the other side claims, and isn't checked.
+    pub(crate) fn lengths(data: &[u8]) -> Option<(usize, u64)> {
         if data.len() < 2 {
             return None;
         }
@@ -98,71 +93,90 @@ impl Frame {
         let masked = (data[1] & 0b1000_0000) != 0;
         let mlen = if masked { 4 } else { 0 };
         let (hlen, plen) = match data[1] & 0b0111_1111 {
-            len @ 0..=125 => (2, len as usize),
-            126 => {
-                if data.len() < 4 {
-                    return None;
-                }
-                (4, BigEndian::read_u16(&data[2..4]) as usize)
-            }
-            127 => {
-                if data.len() < 10 {
-                    return None;
-                }
-                (10, BigEndian::read_u64(&data[2..10]) as usize)
-            }
+            len @ 0..=125 => (2, u64::from(len)),
+            126 => (4, u64::from(BigEndian::read_u16(data.get(2..4)?))),
+            127 => (10, BigEndian::read_u64(data.get(2..10)?)),
             _ => unreachable!(),
         };
 
-        let tlen = hlen + mlen + plen;
-        if data.len() < tlen {
+        if data.len() < hlen + mlen {
+            return None;
+        }
+        Some((hlen + mlen, plen))
+    }
+
+    pub fn is_complete(data: &BytesMut) -> Option<(FrameFlags, usize)> {
+        let (hlen, plen) = Frame::lengths(data)?;
+
+        // Compared as u64, so a hostile length can't overflow anything
+        let available = (data.len() - hlen) as u64;
+        if plen > available {
             return None;
         }
 
-        Some((FrameFlags(data[0]), tlen))
+        Some((FrameFlags(data[0]), hlen + plen as usize))
     }
 
-    pub fn decode(data: &BytesMut) -> Frame {
+    /// Splits a complete frame off the front of `data`. The payload is
+    /// unmasked in place and shares `data`'s memory rather than being copied.
+    ///
+    /// Panics unless `is_complete` said the frame is complete.
+    pub fn decode(data: &mut BytesMut) -> Frame {
+        let (hlen, len) = Frame::lengths(data).expect("Decoding an incomplete frame");
         let flags = FrameFlags(data[0]);
-        let byte_len = data[1] & 0x7F;
         let masked = data[1] & 0x80 != 0;
 
-        let (hlen, len) = match byte_len {
-            0..=125 => (2, byte_len as usize),
-            126 => (4, BigEndian::read_u16(&data[2..4]) as usize),
-            127 => (10, BigEndian::read_u64(&data[2..10]) as usize),
-            _ => unreachable!(),
-        };
-
-        let mut i = hlen;
         let masking_key = if masked {
-            let key = [data[i], data[i + 1], data[i + 2], data[i + 3]];
-            i += 4;
-            Some(key)
+            let i = hlen - 4;
+            Some([data[i], data[i + 1], data[i + 2], data[i + 3]])
         } else {
             None
         };
 
-        let payload = data[i..i + len].iter().cloned().collect();
+        data.split_to(hlen);
+        let mut payload = data.split_to(len as usize);
+        if let Some(key) = masking_key {
+            apply_mask(&mut payload, key);
+        }
 
         Frame {
             flags,
-            masked,
             masking_key,
-            payload,
+            payload: payload.freeze(),
         }
     }
+}
 
-    fn toggle_mask(mut self) -> Frame {
-        let key = self.masking_key
-            .expect("Cannot mask/unmask a frame without masking_key");
+/// Masks or unmasks `buf`, which are the same operation. Works on 8 byte
+/// words once `buf` is aligned, which LLVM can turn into SIMD.
+pub fn apply_mask(buf: &mut [u8], key: [u8; 4]) {
+    let misalignment = buf.as_ptr() as usize % 8;
+    let head = cmp::min(buf.len(), (8 - misalignment) % 8);
+    let (head_bytes, rest) = buf.split_at_mut(head);
+    apply_mask_scalar(head_bytes, key);
+
+    // The words start `head` bytes into the key
+    let mut rotated = [0; 8];
+    for (i, b) in rotated.iter_mut().enumerate() {
+        *b = key[(head + i) % 4];
+    }
+    let key_word = NativeEndian::read_u64(&rotated);
 
-        for (i, b) in self.payload.iter_mut().enumerate() {
-            *b = *b ^ key[i % 4];
-        }
+    let words = rest.len() / 8 * 8;
+    let (word_bytes, tail) = rest.split_at_mut(words);
+    for word in word_bytes.chunks_mut(8) {
+        let masked = NativeEndian::read_u64(word) ^ key_word;
+        NativeEndian::write_u64(word, masked);
+    }
 
-        self.masked = !self.masked;
-        self
+    let mut tail_key = [0; 4];
+    tail_key.copy_from_slice(&rotated[..4]);
+    apply_mask_scalar(tail, tail_key);
+}
+
+fn apply_mask_scalar(buf: &mut [u8], key: [u8; 4]) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b ^= key[i % 4];
     }
 }
 
@@ -254,3 +268,51 @@ pub enum OpCode {
     Rsv14 = 14,
     Rsv15 = 15,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_mask, apply_mask_scalar};
+
+    use rand::{thread_rng, Rng};
+
+    fn check_mask(data: &[u8], offset: usize, key: [u8; 4]) {
+        let mut backing = vec![0u8; offset + data.len()];
+        let buf = &mut backing[offset..];
+        buf.copy_from_slice(data);
+        apply_mask(buf, key);
+
+        let mut expected = data.to_vec();
+        apply_mask_scalar(&mut expected, key);
+        let len = data.len();
+        assert_eq!(&buf[..], &expected[..], "offset {} length {}", offset, len);
+
+        apply_mask(buf, key);
+        assert_eq!(&buf[..], data);
+    }
+
+    #[test]
+    fn word_mask_matches_scalar() {
+        let key = [0x12, 0x34, 0x56, 0x78];
+        let data: Vec<u8> = (0..80u8).map(|i| i.wrapping_mul(37)).collect();
+
+        // Every alignment the word loop can start at, and lengths which end
+        // in the head, on a word boundary and in the tail
+        for offset in 0..8 {
+            for len in 0..=64 {
+                check_mask(&data[..len], offset, key);
+            }
+        }
+    }
+
+    #[test]
+    fn word_mask_matches_scalar_randomized() {
+        let mut rng = thread_rng();
+        for _ in 0..500 {
+            let key: [u8; 4] = rng.gen();
+            let offset = rng.gen_range(0, 8);
+            let mut data = vec![0u8; rng.gen_range(0, 8 * 1024)];
+            rng.fill(&mut data[..]);
+            check_mask(&data, offset, key);
+        }
+    }
+}
@@ -1,30 +1,66 @@
-use discord::gateway::websocket::{Frame, OpCode};
+use discord::gateway::websocket::{CloseCode, Error, Frame, OpCode};
 
 use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+
+/// Longest reason a Close frame can carry, since control frame payloads are
+/// limited to 125 bytes and the status code takes up 2 of them
+pub const MAX_CLOSE_REASON: usize = 123;
 
 #[derive(Debug)]
 pub enum Message {
     Text(String),
-    Binary(Vec<u8>),
-    Close { status: u16, reason: Option<String> },
-    Ping(Vec<u8>),
-    Pong(Vec<u8>),
+    Binary(Bytes),
+    Close { status: CloseCode, reason: Option<String> },
+    Ping(Bytes),
+    Pong(Bytes),
+    /// A streamed text or binary message begins. Only read from decoders in
+    /// streaming mode, never sent.
+    Start(OpCode),
+    /// The next part of a streamed message's payload
+    Chunk(Bytes),
+    /// The streamed message is complete
+    End,
 }
 
 impl Message {
-    pub fn to_frame(self) -> Frame {
+    /// Checks that the message can be put on the wire as it is
+    pub fn validate(&self) -> Result<(), Error> {
         match self {
+            Message::Close { status, reason } => {
+                if !status.is_sendable() {
+                    return Err(Error::UnsendableCloseCode(*status));
+                }
+                let len = reason.as_ref().map(|r| r.len()).unwrap_or(0);
+                if len > MAX_CLOSE_REASON {
+                    return Err(Error::CloseReasonTooLong(len));
+                }
+                Ok(())
+            }
+            Message::Start(_) | Message::Chunk(_) | Message::End => Err(Error::UnsendableMessage),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails on the parts of streamed messages, which only come from reading
+    pub fn to_frame(self) -> Result<Frame, Error> {
+        let frame = match self {
             Message::Text(text) => Frame::new(OpCode::Text, text),
             Message::Binary(data) => Frame::new(OpCode::Binary, data),
             Message::Close { status, reason } => {
-                let mut statb = [0; 2];
-                BigEndian::write_u16(&mut statb, status);
-                let mut buf = Vec::with_capacity(reason.map(|r| r.len()).unwrap_or(0) + 2);
+                let reason = reason.unwrap_or_default();
+                let mut buf = vec![0; 2];
+                BigEndian::write_u16(&mut buf, status.into());
+                buf.extend_from_slice(reason.as_bytes());
 
                 Frame::new(OpCode::Close, buf)
             }
             Message::Ping(data) => Frame::new(OpCode::Ping, data),
             Message::Pong(data) => Frame::new(OpCode::Pong, data),
-        }
+            Message::Start(_) | Message::Chunk(_) | Message::End => {
+                return Err(Error::UnsendableMessage)
+            }
+        };
+        Ok(frame)
     }
 }
@@ -1,13 +1,27 @@
+pub use discord::gateway::websocket::accept::{accept, listen};
 pub use discord::gateway::websocket::client::{Client, Reader, Writer};
+pub use discord::gateway::websocket::close::CloseCode;
 pub use discord::gateway::websocket::codec::{ClientCodec, ClientDecoder, ClientEncoder};
-pub use discord::gateway::websocket::connect::{connect, connect_with_auth, connect_with_settings};
+pub use discord::gateway::websocket::codec::{ServerCodec, ServerDecoder, ServerEncoder};
+pub use discord::gateway::websocket::connect::{connect, connect_over, connect_with_auth};
+pub use discord::gateway::websocket::connect::{connect_with_settings, ConnectSettings};
+pub use discord::gateway::websocket::connect::ConnectTimeouts;
+pub use discord::gateway::websocket::deflate::{DeflateConfig, DeflateSettings};
 pub use discord::gateway::websocket::error::Error;
 pub use discord::gateway::websocket::frame::{Frame, OpCode};
 pub use discord::gateway::websocket::message::Message;
+pub use discord::gateway::websocket::tls::TlsSettings;
 
+pub mod accept;
 pub mod client;
+pub mod close;
 pub mod codec;
 pub mod connect;
+pub mod deflate;
 pub mod error;
 pub mod frame;
 pub mod message;
+pub mod tls;
+
+#[cfg(test)]
+pub mod tests;
@@ -0,0 +1,697 @@
+//! Conformance checks modelled on the Autobahn testsuite's cases. The codec
+//! cases feed raw server frames to a `ClientDecoder`, the others run over a
+//! real connection to the in-process server. The connection helpers are
+//! shared with the gateway's tests.
+
+use discord::gateway::websocket::{connect_over, listen};
+use discord::gateway::websocket::{Client, ClientDecoder, CloseCode, ConnectSettings};
+use discord::gateway::websocket::{DeflateConfig, DeflateSettings};
+use discord::gateway::websocket::{Error, Frame, Message, OpCode, Reader};
+use discord::proxy::ProxySettings;
+use error::Error as DError;
+
+use std::sync::mpsc;
+
+use bytes::{Bytes, BytesMut};
+use futures::future;
+use futures::prelude::*;
+use http::Uri;
+use rand::{thread_rng, Rng};
+use tokio;
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+use tokio_io::codec::Decoder;
+
+/// Runs `f` on a fresh runtime and hands back its result
+pub fn run<F>(f: F) -> Result<F::Item, F::Error>
+where
+    F: Future + Send + 'static,
+    F::Item: Send,
+    F::Error: Send,
+{
+    let (tx, rx) = mpsc::channel();
+    tokio::run(f.then(move |result| {
+        tx.send(result).unwrap();
+        Ok(())
+    }));
+    rx.recv().unwrap()
+}
+
+/// Connects directly, whatever proxy the environment names
+pub fn settings() -> ConnectSettings {
+    ConnectSettings {
+        proxy: ProxySettings::default(),
+        ..Default::default()
+    }
+}
+
+/// Connects a client to a fresh in-process server. Yields the client and
+/// the server's end of the connection.
+pub fn pair() -> impl Future<Item = (Client, Client), Error = DError> {
+    pair_with(settings())
+}
+
+/// Like `pair`, with the client connecting using `settings`
+pub fn pair_with(
+    settings: ConnectSettings,
+) -> impl Future<Item = (Client, Client), Error = DError> {
+    future::lazy(move || {
+        let (addr, clients) = listen(&"127.0.0.1:0".parse().unwrap())?;
+        let server = clients.into_future().map_err(|(e, _)| e);
+        let server = server.map(|(client, _)| client.expect("Listener stopped"));
+
+        let uri: Uri = format!("ws://{}/", addr).parse().unwrap();
+        let client = TcpStream::connect(&addr)
+            .map_err(DError::from)
+            .and_then(move |stream| connect_over(stream, uri, settings));
+
+        Ok::<_, DError>(client.join(server))
+    })
+    .flatten()
+}
+
+/// The next message from `reader`, which has to be there
+pub fn next(reader: Reader) -> impl Future<Item = (Message, Reader), Error = DError> {
+    reader
+        .into_future()
+        .map_err(|(e, _)| e.into())
+        .map(|(message, reader)| (message.expect("Connection closed"), reader))
+}
+
+/// Raw bytes of a frame as a server sends it
+fn frame(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Frame::new(OpCode::Binary, Bytes::from(payload));
+    frame.flags.0 = flags;
+    let mut buf = BytesMut::with_capacity(frame.frame_size());
+    frame.encode(&mut buf);
+    buf.to_vec()
+}
+
+fn close_frame(code: u16, reason: &[u8]) -> Vec<u8> {
+    let mut payload = vec![(code >> 8) as u8, code as u8];
+    payload.extend_from_slice(reason);
+    frame(0x88, &payload)
+}
+
+/// Decodes everything in `data`, stopping at the first error
+fn decode_with(mut decoder: ClientDecoder, data: &[u8]) -> Result<Vec<Message>, Error> {
+    let mut buf = BytesMut::from(data);
+    let mut messages = vec![];
+    while let Some(message) = decoder.decode(&mut buf)? {
+        messages.push(message);
+    }
+    assert!(buf.is_empty(), "{} bytes left undecoded", buf.len());
+    Ok(messages)
+}
+
+fn decode(data: &[u8]) -> Result<Vec<Message>, Error> {
+    decode_with(ClientDecoder::new(), data)
+}
+
+/// Feeds `data` one byte at a time, like a very slow connection
+fn decode_bytewise(data: &[u8]) -> Result<Vec<Message>, Error> {
+    let mut decoder = ClientDecoder::new();
+    let mut buf = BytesMut::new();
+    let mut messages = vec![];
+    for &byte in data {
+        buf.extend_from_slice(&[byte]);
+        while let Some(message) = decoder.decode(&mut buf)? {
+            messages.push(message);
+        }
+    }
+    Ok(messages)
+}
+
+fn decode_err(data: &[u8]) -> Error {
+    match decode(data) {
+        Ok(messages) => panic!("Decoded {:?}", messages),
+        Err(e) => e,
+    }
+}
+
+fn concat(frames: &[Vec<u8>]) -> Vec<u8> {
+    frames.iter().flat_map(|f| f.iter().cloned()).collect()
+}
+
+fn expect_text(message: &Message, expected: &str) {
+    match message {
+        Message::Text(text) => assert_eq!(text, expected),
+        other => panic!("Expected text, got {:?}", other),
+    }
+}
+
+fn expect_close(message: &Message, code: CloseCode, expected: Option<&str>) {
+    match message {
+        Message::Close { status, reason } => {
+            assert_eq!(*status, code);
+            assert_eq!(reason.as_ref().map(|r| &r[..]), expected);
+        }
+        other => panic!("Expected close, got {:?}", other),
+    }
+}
+
+// Autobahn 1.x, framing
+
+#[test]
+fn payload_lengths() {
+    for &len in &[0, 125, 126, 127, 128, 65535, 65536] {
+        let payload = vec![b'*'; len];
+        let messages = decode(&frame(0x82, &payload)).unwrap();
+        match messages[..] {
+            [Message::Binary(ref data)] => assert_eq!(&data[..], &payload[..]),
+            ref other => panic!("Expected binary of {}, got {:?}", len, other),
+        }
+
+        let text = String::from_utf8(payload).unwrap();
+        let messages = decode(&frame(0x81, text.as_bytes())).unwrap();
+        expect_text(&messages[0], &text);
+    }
+}
+
+#[test]
+fn bytewise_delivery() {
+    let data = concat(&[
+        frame(0x81, &[b'a'; 126]),
+        frame(0x89, b"ping"),
+        frame(0x82, &[]),
+    ]);
+    let messages = decode_bytewise(&data).unwrap();
+    assert_eq!(messages.len(), 3);
+    expect_text(&messages[0], &"a".repeat(126));
+    match messages[1] {
+        Message::Ping(ref data) => assert_eq!(&data[..], b"ping"),
+        ref other => panic!("Expected ping, got {:?}", other),
+    }
+}
+
+#[test]
+fn length_with_top_bit_set() {
+    let data = [0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 1];
+    match decode_err(&data) {
+        Error::InvalidFrameLength(len) => assert_eq!(len, 0x8000_0000_0000_0001),
+        e => panic!("{:?}", e),
+    }
+}
+
+// Autobahn 2.x, pings
+
+#[test]
+fn ping_payloads() {
+    let messages = decode(&frame(0x89, &[0xFE; 125])).unwrap();
+    match messages[..] {
+        [Message::Ping(ref data)] => assert_eq!(&data[..], &[0xFE; 125][..]),
+        ref other => panic!("Expected ping, got {:?}", other),
+    }
+
+    match decode_err(&frame(0x89, &[0xFE; 126])) {
+        Error::ControlFrameTooLarge => (),
+        e => panic!("{:?}", e),
+    }
+    match decode_err(&frame(0x09, b"fragmented")) {
+        Error::NonFinalControlFrame => (),
+        e => panic!("{:?}", e),
+    }
+}
+
+// Autobahn 3.x, reserved bits
+
+#[test]
+fn reserved_bits() {
+    for &flags in &[0xA1, 0x91, 0xB1, 0xF1, 0xD9] {
+        match decode_err(&frame(flags, b"Hello")) {
+            Error::ReservedBitsSet => (),
+            e => panic!("{:x}: {:?}", flags, e),
+        }
+    }
+
+    // Without a negotiated permessage-deflate, RSV1 is just as reserved
+    match decode_err(&frame(0xC1, b"Hello")) {
+        Error::UnexpectedCompression => (),
+        e => panic!("{:?}", e),
+    }
+}
+
+// Autobahn 4.x, opcodes
+
+#[test]
+fn reserved_opcodes() {
+    for opcode in (3..8).chain(11..16) {
+        match decode_err(&frame(0x80 | opcode, b"")) {
+            Error::UnexpectedFrame(op, _) => assert_eq!(op as u8, opcode),
+            e => panic!("{}: {:?}", opcode, e),
+        }
+    }
+}
+
+// Autobahn 5.x, fragmentation
+
+#[test]
+fn fragmented_messages() {
+    let data = concat(&[
+        frame(0x01, b"fragment1"),
+        frame(0x89, b"ping"),
+        frame(0x00, b"fragment2"),
+        frame(0x80, b"fragment3"),
+    ]);
+    let messages = decode(&data).unwrap();
+    assert_eq!(messages.len(), 2);
+    match messages[0] {
+        Message::Ping(_) => (),
+        ref other => panic!("Expected ping, got {:?}", other),
+    }
+    expect_text(&messages[1], "fragment1fragment2fragment3");
+    assert_eq!(decode_bytewise(&data).unwrap().len(), 2);
+
+    // Continuations with nothing to continue
+    match decode_err(&frame(0x80, b"orphan")) {
+        Error::UnexpectedFrame(OpCode::Continuation, _) => (),
+        e => panic!("{:?}", e),
+    }
+    match decode_err(&concat(&[frame(0x00, b"a"), frame(0x80, b"b")])) {
+        Error::UnexpectedFrame(OpCode::Continuation, _) => (),
+        e => panic!("{:?}", e),
+    }
+
+    // A new message before the last one finished
+    match decode_err(&concat(&[frame(0x01, b"a"), frame(0x81, b"b")])) {
+        Error::UnexpectedFrame(OpCode::Text, _) => (),
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn streamed_messages_limit_frames() {
+    // Over the message limit whole, but every frame is under it
+    let data = concat(&[
+        frame(0x02, &[0; 6]),
+        frame(0x00, &[0; 6]),
+        frame(0x80, &[0; 6]),
+    ]);
+    match decode_with(ClientDecoder::with_limit(10), &data) {
+        Err(Error::FrameTooLarge(12)) => (),
+        other => panic!("{:?}", other),
+    }
+
+    let parts = decode_with(ClientDecoder::with_limit(10).streaming(), &data).unwrap();
+    let chunks = parts.iter().fold(0, |len, part| match *part {
+        Message::Chunk(ref chunk) => len + chunk.len(),
+        _ => len,
+    });
+    assert_eq!(chunks, 18);
+
+    match decode_with(ClientDecoder::with_limit(4).streaming(), &data) {
+        Err(Error::OversizedFrame(_)) => (),
+        other => panic!("{:?}", other),
+    }
+}
+
+// Autobahn 6.x, UTF-8 handling
+
+#[test]
+fn invalid_utf8() {
+    let bad: &[&[u8]] = &[
+        b"\xCE\xBA\xE1\xBD\xB9\xCF\x83\xCE\xBC\xCE\xB5\xED\xA0\x80",
+        b"\xFF",
+        b"\xC0\xAF",
+        b"\xF4\x90\x80\x80",
+        b"\xCE",
+    ];
+    for payload in bad {
+        match decode_err(&frame(0x81, payload)) {
+            Error::TextFrameNotUtf8 => (),
+            e => panic!("{:?}: {:?}", payload, e),
+        }
+    }
+}
+
+#[test]
+fn characters_split_across_frames() {
+    // "κόσμε", split inside its second character
+    let text = "κόσμε".as_bytes();
+    let data = concat(&[frame(0x01, &text[..3]), frame(0x80, &text[3..])]);
+    expect_text(&decode(&data).unwrap()[0], "κόσμε");
+
+    let decoder = ClientDecoder::new().streaming();
+    let parts = decode_with(decoder, &data).unwrap();
+    let mut streamed = vec![];
+    for part in &parts {
+        if let Message::Chunk(ref chunk) = *part {
+            streamed.extend_from_slice(chunk);
+        }
+    }
+    assert_eq!(&streamed[..], text);
+
+    // Only a character which gets finished may be split
+    let data = concat(&[frame(0x01, &text[..3]), frame(0x80, b"x")]);
+    match decode_err(&data) {
+        Error::TextFrameNotUtf8 => (),
+        e => panic!("{:?}", e),
+    }
+    let data = concat(&[frame(0x01, &text[..3]), frame(0x80, b"")]);
+    match decode_with(ClientDecoder::new().streaming(), &data) {
+        Err(Error::TextFrameNotUtf8) => (),
+        other => panic!("{:?}", other),
+    }
+}
+
+// Autobahn 7.x, closing
+
+#[test]
+fn close_payloads() {
+    expect_close(
+        &decode(&frame(0x88, b"")).unwrap()[0],
+        CloseCode::NoStatus,
+        None,
+    );
+    expect_close(
+        &decode(&close_frame(1000, b"bye")).unwrap()[0],
+        CloseCode::Normal,
+        Some("bye"),
+    );
+
+    match decode_err(&frame(0x88, b"\x03")) {
+        Error::BadCloseFrame => (),
+        e => panic!("{:?}", e),
+    }
+    match decode_err(&close_frame(
+        1000,
+        b"\xCE\xBA\xE1\xBD\xB9\xCF\x83\xCE\xBC\xCE\xB5\xED\xA0\x80",
+    )) {
+        Error::TextFrameNotUtf8 => (),
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn close_codes() {
+    for &code in &[
+        1000, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011, 3000, 3999, 4000, 4999,
+    ] {
+        let messages = decode(&close_frame(code, b"")).unwrap();
+        match messages[..] {
+            [Message::Close { status, .. }] => assert_eq!(u16::from(status), code),
+            ref other => panic!("{}: {:?}", code, other),
+        }
+    }
+
+    for &code in &[
+        0, 999, 1004, 1005, 1006, 1015, 1016, 1100, 2000, 2999, 5000, 65535,
+    ] {
+        match decode_err(&close_frame(code, b"")) {
+            Error::InvalidCloseCode(c) => assert_eq!(c, code),
+            e => panic!("{}: {:?}", code, e),
+        }
+    }
+}
+
+// RFC 6455 Section 5.1
+
+#[test]
+fn masked_server_frames() {
+    let mut buf = BytesMut::new();
+    Frame::new(OpCode::Text, "masked")
+        .with_mask([1, 2, 3, 4])
+        .encode(&mut buf);
+    match decode_err(&buf) {
+        Error::MaskedFrame => (),
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn failure_close_codes() {
+    assert_eq!(
+        Error::ControlFrameTooLarge.close_code(),
+        Some(CloseCode::ProtocolError)
+    );
+    assert_eq!(
+        Error::ReservedBitsSet.close_code(),
+        Some(CloseCode::ProtocolError)
+    );
+    assert_eq!(
+        Error::InvalidCloseCode(1005).close_code(),
+        Some(CloseCode::ProtocolError)
+    );
+    assert_eq!(
+        Error::InvalidFrameLength(1 << 63).close_code(),
+        Some(CloseCode::ProtocolError)
+    );
+    assert_eq!(
+        Error::TextFrameNotUtf8.close_code(),
+        Some(CloseCode::InvalidPayload)
+    );
+    assert_eq!(
+        Error::BadCompression.close_code(),
+        Some(CloseCode::InvalidPayload)
+    );
+    assert_eq!(
+        Error::OversizedFrame(1 << 40).close_code(),
+        Some(CloseCode::MessageTooBig)
+    );
+    assert_eq!(
+        Error::FrameTooLarge(1 << 20).close_code(),
+        Some(CloseCode::MessageTooBig)
+    );
+    assert_eq!(Error::BadUpgrade.close_code(), None);
+    assert_eq!(Error::UnsendableMessage.close_code(), None);
+}
+
+// A whole connection, RFC 6455 Sections 4, 5.5 and 7
+
+#[test]
+fn round_trip() {
+    let test = pair().and_then(|(client, server)| {
+        async_block! {
+            let Client { reader: client_reader, writer: client_writer, .. } = client;
+            let Client { reader: server_reader, writer: server_writer, headers } = server;
+            assert_eq!(headers["sec-websocket-version"], "13");
+
+            let client_writer = await!(client_writer.send(Message::Text("hello".into())))?;
+            let (message, server_reader) = await!(next(server_reader))?;
+            expect_text(&message, "hello");
+
+            let server_writer = await!(server_writer.send(Message::Text("hello back".into())))?;
+            let (message, client_reader) = await!(next(client_reader))?;
+            expect_text(&message, "hello back");
+
+            // Answering pings is left to whoever uses the client
+            let ping = Message::Ping("are you there".into());
+            let client_writer = await!(client_writer.send(ping))?;
+            let (message, server_reader) = await!(next(server_reader))?;
+            let payload = match message {
+                Message::Ping(payload) => payload,
+                other => panic!("Expected ping, got {:?}", other),
+            };
+            let server_writer = await!(server_writer.send(Message::Pong(payload)))?;
+            let (message, client_reader) = await!(next(client_reader))?;
+            match message {
+                Message::Pong(ref payload) => assert_eq!(&payload[..], b"are you there"),
+                other => panic!("Expected pong, got {:?}", other),
+            }
+
+            let close = Message::Close {
+                status: CloseCode::Normal,
+                reason: Some("done".into()),
+            };
+            await!(client_writer.send(close))?;
+            let (message, _) = await!(next(server_reader))?;
+            expect_close(&message, CloseCode::Normal, Some("done"));
+
+            let close = Message::Close {
+                status: CloseCode::Normal,
+                reason: None,
+            };
+            await!(server_writer.send(close))?;
+            let (message, _) = await!(next(client_reader))?;
+            expect_close(&message, CloseCode::Normal, None);
+
+            Ok::<_, DError>(())
+        }
+    });
+
+    run(test).unwrap();
+}
+
+// Compressed messages, RFC 7692
+
+#[test]
+fn deflate_round_trip() {
+    let offers = vec![
+        DeflateSettings::default(),
+        DeflateSettings {
+            client_no_context_takeover: true,
+            server_no_context_takeover: true,
+            server_max_window_bits: None,
+        },
+    ];
+
+    for offer in offers {
+        let expected = DeflateConfig {
+            client_no_context_takeover: offer.client_no_context_takeover,
+            server_no_context_takeover: offer.server_no_context_takeover,
+            server_max_window_bits: 15,
+        };
+        let settings = ConnectSettings {
+            deflate: Some(offer),
+            ..settings()
+        };
+
+        // Barely compresses, so it still takes several frames
+        let mut data = vec![0; 10_000];
+        thread_rng().fill(&mut data[..]);
+        let data = Bytes::from(data);
+
+        let test = pair_with(settings).and_then(move |(client, server)| {
+            async_block! {
+                let Client { reader: client_reader, writer: client_writer, headers } = client;
+                let Client { reader: server_reader, writer: server_writer, .. } = server;
+                assert_eq!(
+                    DeflateConfig::accept(headers["sec-websocket-extensions"].as_bytes()),
+                    Some(expected)
+                );
+
+                // Each message is sent twice, so the second goes out with
+                // the first in the window unless context takeover is off
+                let text = "All work and no play makes Jack a dull boy. ".repeat(500);
+                let mut client_writer = client_writer;
+                let mut server_reader = server_reader;
+                for _ in 0..2 {
+                    client_writer = await!(client_writer.send(Message::Text(text.clone())))?;
+                    let (message, reader) = await!(next(server_reader))?;
+                    expect_text(&message, &text);
+                    server_reader = reader;
+                }
+
+                let mut server_writer = server_writer;
+                let mut client_reader = client_reader;
+                for _ in 0..2 {
+                    server_writer = await!(server_writer.send(Message::Binary(data.clone())))?;
+                    let (message, reader) = await!(next(client_reader))?;
+                    match message {
+                        Message::Binary(ref received) => assert_eq!(received, &data),
+                        other => panic!("Expected binary, got {:?}", other),
+                    }
+                    client_reader = reader;
+                }
+
+                // Control frames are never compressed
+                await!(server_writer.send(Message::Ping("ping".into())))?;
+                match await!(next(client_reader))?.0 {
+                    Message::Ping(ref payload) => assert_eq!(&payload[..], b"ping"),
+                    other => panic!("Expected ping, got {:?}", other),
+                }
+
+                Ok::<_, DError>(())
+            }
+        });
+
+        run(test).unwrap();
+    }
+}
+
+#[test]
+fn deflate_declined() {
+    // The server can't honour a smaller window, so it turns the offer down
+    let settings = ConnectSettings {
+        deflate: Some(DeflateSettings {
+            server_max_window_bits: Some(10),
+            ..Default::default()
+        }),
+        ..settings()
+    };
+
+    let test = pair_with(settings).and_then(|(client, server)| {
+        assert!(!client.headers.contains_key("sec-websocket-extensions"));
+        let send = client.writer.send(Message::Text("plain".into()));
+        send.map_err(DError::from)
+            .and_then(|_| next(server.reader))
+            .map(|(message, _)| expect_text(&message, "plain"))
+    });
+
+    run(test).unwrap();
+}
+
+// Failing the connection, RFC 6455 Section 7.1.7
+
+#[test]
+fn client_fails_connection_on_protocol_error() {
+    let test = pair().and_then(|(client, server)| {
+        let Client { reader, writer, .. } = server;
+
+        // Too large for a control frame, which the encoder doesn't check
+        let ping = Message::Ping(Bytes::from(&[0; 200][..]));
+        let read_client = client.reader.into_future().map_err(|(e, _)| e);
+        let read_server = writer
+            .send(ping)
+            .map_err(DError::from)
+            .and_then(|_| reader.into_future().map_err(|(e, _)| e.into()));
+
+        read_client.then(Ok::<_, DError>).join(read_server)
+    });
+
+    let (client_result, (close, _)) = run(test).unwrap();
+    match client_result {
+        Err(Error::ControlFrameTooLarge) => (),
+        other => panic!("{:?}", other.map(|(m, _)| m)),
+    }
+    expect_close(&close.unwrap(), CloseCode::ProtocolError, None);
+}
+
+#[test]
+fn server_fails_connection_on_invalid_utf8() {
+    const REQUEST: &str = "GET / HTTP/1.1\r\n\
+                           Host: localhost\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\
+                           \r\n";
+
+    const RESPONSE: &str = "HTTP/1.1 101 Switching Protocols\r\n\
+                            Upgrade: websocket\r\n\
+                            Connection: Upgrade\r\n\
+                            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+                            \r\n";
+
+    let test = future::lazy(|| {
+        let (addr, clients) = listen(&"127.0.0.1:0".parse().unwrap())?;
+        let server = clients
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(client, _)| {
+                let client = client.expect("Listener stopped");
+                client.reader.into_future().then(|result| match result {
+                    Err((e, _)) => Ok::<_, DError>(e),
+                    Ok((message, _)) => panic!("Read {:?}", message),
+                })
+            });
+
+        // A client which sends invalid UTF-8, by hand
+        let mut bad_text = Frame::new(OpCode::Text, &b"\xFF"[..]);
+        bad_text.masking_key = Some([7, 7, 7, 7]);
+        let mut frame = BytesMut::with_capacity(bad_text.frame_size());
+        bad_text.encode(&mut frame);
+
+        let client = TcpStream::connect(&addr)
+            .and_then(|stream| write_all(stream, REQUEST))
+            .and_then(|(stream, _)| read_exact(stream, vec![0; RESPONSE.len()]))
+            .and_then(move |(stream, response)| {
+                assert_eq!(String::from_utf8_lossy(&response), RESPONSE);
+                write_all(stream, frame)
+            })
+            .and_then(|(stream, _)| read_exact(stream, [0; 4]))
+            .map(|(_, close)| close)
+            .map_err(DError::from);
+
+        Ok::<_, DError>(client.join(server))
+    })
+    .flatten();
+
+    // Close with 1007
+    let (close, error) = run(test).unwrap();
+    assert_eq!(close, [0x88, 0x02, 0x03, 0xEF]);
+    match error {
+        Error::TextFrameNotUtf8 => (),
+        e => panic!("{:?}", e),
+    }
+}
@@ -1,6 +1,8 @@
 pub mod api;
 pub mod gateway;
 pub mod models;
+pub mod net;
+pub mod proxy;
 
 /// The discord epoch represented as a unix epoch (ms)
 pub const EPOCH: i64 = 1420070400000;
@@ -0,0 +1,36 @@
+use discord::models::snowflake::Snowflake;
+use discord::models::user::User;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Channel {
+    pub id: Snowflake,
+    #[serde(rename = "type")]
+    pub type_: i32,
+    pub guild_id: Option<Snowflake>,
+    pub position: Option<i32>,
+    #[serde(default)]
+    pub permission_overwrites: Vec<Overwrite>,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub nsfw: bool,
+    pub last_message_id: Option<Snowflake>,
+    pub bitrate: Option<i32>,
+    pub user_limit: Option<i32>,
+    #[serde(default)]
+    pub recipients: Vec<User>,
+    pub icon: Option<String>,
+    pub owner_id: Option<Snowflake>,
+    pub application_id: Option<Snowflake>,
+    pub parent_id: Option<Snowflake>,
+    pub last_pin_timestamp: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Overwrite {
+    pub id: Snowflake,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub allow: u64,
+    pub deny: u64,
+}
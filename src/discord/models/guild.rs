@@ -0,0 +1,91 @@
+use discord::models::channel::Channel;
+use discord::models::snowflake::Snowflake;
+use discord::models::status::Presence;
+use discord::models::user::User;
+use discord::models::voice::VoiceState;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Guild {
+    pub id: Snowflake,
+    pub name: String,
+    pub icon: Option<String>,
+    pub splash: Option<String>,
+    pub owner_id: Snowflake,
+    pub region: String,
+    pub afk_channel_id: Option<Snowflake>,
+    pub afk_timeout: i32,
+    pub verification_level: i32,
+    pub default_message_notifications: i32,
+    pub explicit_content_filter: i32,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    #[serde(default)]
+    pub emojis: Vec<Emoji>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    pub mfa_level: i32,
+    pub system_channel_id: Option<Snowflake>,
+
+    // Only sent with GUILD_CREATE
+    pub joined_at: Option<String>,
+    pub large: Option<bool>,
+    pub member_count: Option<i32>,
+    #[serde(default)]
+    pub unavailable: bool,
+    #[serde(default)]
+    pub voice_states: Vec<VoiceState>,
+    #[serde(default)]
+    pub members: Vec<Member>,
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+    #[serde(default)]
+    pub presences: Vec<Presence>,
+}
+
+/// A guild which is still loading or is in an outage
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UnavailableGuild {
+    pub id: Snowflake,
+    #[serde(default)]
+    pub unavailable: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Role {
+    pub id: Snowflake,
+    pub name: String,
+    pub color: u32,
+    pub hoist: bool,
+    pub position: i32,
+    pub permissions: u64,
+    pub managed: bool,
+    pub mentionable: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Emoji {
+    pub id: Option<Snowflake>,
+    pub name: String,
+    #[serde(default)]
+    pub roles: Vec<Snowflake>,
+    pub user: Option<User>,
+    #[serde(default)]
+    pub require_colons: bool,
+    #[serde(default)]
+    pub managed: bool,
+    #[serde(default)]
+    pub animated: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Member {
+    pub user: User,
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<Snowflake>,
+    pub joined_at: String,
+    #[serde(default)]
+    pub deaf: bool,
+    #[serde(default)]
+    pub mute: bool,
+}
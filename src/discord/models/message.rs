@@ -0,0 +1,63 @@
+use discord::models::snowflake::Snowflake;
+use discord::models::user::User;
+
+use serde_json as json;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Message {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub author: User,
+    pub content: String,
+    pub timestamp: String,
+    pub edited_timestamp: Option<String>,
+    #[serde(default)]
+    pub tts: bool,
+    #[serde(default)]
+    pub mention_everyone: bool,
+    #[serde(default)]
+    pub mentions: Vec<User>,
+    #[serde(default)]
+    pub mention_roles: Vec<Snowflake>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    // TODO: Embed object
+    #[serde(default)]
+    pub embeds: Vec<json::Value>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    pub nonce: Option<json::Value>,
+    #[serde(default)]
+    pub pinned: bool,
+    pub webhook_id: Option<Snowflake>,
+    #[serde(rename = "type")]
+    pub type_: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Attachment {
+    pub id: Snowflake,
+    pub filename: String,
+    pub size: u64,
+    pub url: String,
+    pub proxy_url: String,
+    pub height: Option<i32>,
+    pub width: Option<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Reaction {
+    pub count: i32,
+    pub me: bool,
+    pub emoji: PartialEmoji,
+}
+
+/// The emoji of a reaction. Custom emoji which were deleted have no name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PartialEmoji {
+    pub id: Option<Snowflake>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub animated: bool,
+}
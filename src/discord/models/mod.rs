@@ -1,3 +1,8 @@
+pub mod channel;
 pub mod gateway;
+pub mod guild;
+pub mod message;
 pub mod snowflake;
 pub mod status;
+pub mod user;
+pub mod voice;
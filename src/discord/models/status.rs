@@ -1,4 +1,5 @@
 use discord::models::snowflake::Snowflake;
+use discord::models::user::PartialUser;
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum UserStatus {
@@ -47,3 +48,14 @@ pub struct ActivityAssets {
     pub small_image: Option<String>,
     pub small_text: Option<String>,
 }
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Presence {
+    pub user: PartialUser,
+    #[serde(default)]
+    pub roles: Vec<Snowflake>,
+    pub game: Option<Activity>,
+    pub guild_id: Option<Snowflake>,
+    pub status: UserStatus,
+    pub nick: Option<String>,
+}
@@ -0,0 +1,24 @@
+use discord::models::snowflake::Snowflake;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct User {
+    pub id: Snowflake,
+    pub username: String,
+    pub discriminator: String,
+    pub avatar: Option<String>,
+    #[serde(default)]
+    pub bot: bool,
+    pub mfa_enabled: Option<bool>,
+    pub verified: Option<bool>,
+    pub email: Option<String>,
+}
+
+/// A user object where only the id is guaranteed, as sent in presence updates
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PartialUser {
+    pub id: Snowflake,
+    pub username: Option<String>,
+    pub discriminator: Option<String>,
+    pub avatar: Option<String>,
+    pub bot: Option<bool>,
+}
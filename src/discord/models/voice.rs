@@ -0,0 +1,16 @@
+use discord::models::guild::Member;
+use discord::models::snowflake::Snowflake;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VoiceState {
+    pub guild_id: Option<Snowflake>,
+    pub channel_id: Option<Snowflake>,
+    pub user_id: Snowflake,
+    pub member: Option<Member>,
+    pub session_id: String,
+    pub deaf: bool,
+    pub mute: bool,
+    pub self_deaf: bool,
+    pub self_mute: bool,
+    pub suppress: bool,
+}
@@ -0,0 +1,212 @@
+use error::Error;
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::sync::oneshot;
+use tokio::net::{ConnectFuture, TcpStream};
+use tokio::timer::Delay;
+
+/// How long a connection attempt gets before the next address is tried
+/// alongside it (RFC 8305 Section 5)
+pub const ATTEMPT_DELAY_MS: u64 = 250;
+
+/// Looks up `host` without blocking the reactor. The system resolver has no
+/// async interface, so the lookup runs on its own thread.
+pub fn resolve(host: &str, port: u16) -> impl Future<Item = Vec<SocketAddr>, Error = Error> {
+    // Uris keep the brackets around IPv6 literals
+    let host = host.trim_matches(|c| c == '[' || c == ']').to_string();
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Either::A(future::ok(vec![SocketAddr::new(ip, port)]));
+    }
+
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let addrs = (&host[..], port).to_socket_addrs().map(|a| a.collect());
+        let _ = tx.send(addrs);
+    });
+
+    let addrs = rx
+        .map_err(|_| Error::FutureError)
+        .and_then(|addrs: io::Result<Vec<_>>| addrs.map_err(Error::from));
+    Either::B(addrs)
+}
+
+/// Connects to whichever of `addrs` answers first, racing IPv6 against IPv4
+pub fn connect_any(addrs: Vec<SocketAddr>) -> HappyEyeballs {
+    connect_with(addrs, TcpStream::connect)
+}
+
+fn connect_with<C>(addrs: Vec<SocketAddr>, connect: fn(&SocketAddr) -> C) -> HappyEyeballs<C> {
+    HappyEyeballs {
+        addrs: interleave(addrs),
+        attempts: Vec::new(),
+        next_attempt: Delay::new(Instant::now()),
+        last_error: None,
+        connect,
+    }
+}
+
+/// Alternates between address families, starting with IPv6 and otherwise
+/// keeping the resolver's order (RFC 8305 Section 4)
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+
+    let mut sorted = VecDeque::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
+}
+
+/// Starts a connection attempt every `ATTEMPT_DELAY_MS`, or as soon as the
+/// previous one failed, and resolves with the first to succeed
+pub struct HappyEyeballs<C = ConnectFuture> {
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, C)>,
+    next_attempt: Delay,
+    last_error: Option<io::Error>,
+    connect: fn(&SocketAddr) -> C,
+}
+
+impl<C> Future for HappyEyeballs<C>
+where
+    C: Future<Item = TcpStream, Error = io::Error>,
+{
+    type Item = TcpStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, Error> {
+        loop {
+            let waited = self.next_attempt.poll()?.is_ready();
+            if (waited || self.attempts.is_empty()) && !self.addrs.is_empty() {
+                let addr = self.addrs.pop_front().unwrap();
+                trace!("Connecting to {}", addr);
+                self.attempts.push((addr, (self.connect)(&addr)));
+
+                let delay = Duration::from_millis(ATTEMPT_DELAY_MS);
+                self.next_attempt = Delay::new(Instant::now() + delay);
+                continue;
+            }
+
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        debug!("Connecting to {} failed: {}", self.attempts[i].0, e);
+                        self.attempts.swap_remove(i);
+                        self.last_error = Some(e);
+                    }
+                }
+            }
+
+            if !self.attempts.is_empty() {
+                return Ok(Async::NotReady);
+            }
+            if self.addrs.is_empty() {
+                let e = self.last_error.take().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+                });
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_with, interleave, ATTEMPT_DELAY_MS};
+    use discord::gateway::websocket::tests::run;
+
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::{Duration, Instant};
+
+    use futures::future;
+    use futures::prelude::*;
+    use tokio::net::TcpStream;
+
+    type Attempt = Box<Future<Item = TcpStream, Error = io::Error> + Send>;
+
+    /// Never gets an answer from IPv6 addresses
+    fn hanging_v6(addr: &SocketAddr) -> Attempt {
+        if addr.is_ipv6() {
+            Box::new(future::empty())
+        } else {
+            Box::new(TcpStream::connect(addr))
+        }
+    }
+
+    /// Gets refused by IPv6 addresses straight away
+    fn refused_v6(addr: &SocketAddr) -> Attempt {
+        if addr.is_ipv6() {
+            let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+            Box::new(future::err(refused))
+        } else {
+            Box::new(TcpStream::connect(addr))
+        }
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleaving() {
+        let resolved = addrs(&["1.0.0.1:1", "1.0.0.2:1", "[::1]:1", "[::2]:1", "1.0.0.3:1"]);
+        let expected = addrs(&["[::1]:1", "1.0.0.1:1", "[::2]:1", "1.0.0.2:1", "1.0.0.3:1"]);
+        assert_eq!(Vec::from(interleave(resolved)), expected);
+
+        let v4_only = addrs(&["1.0.0.2:1", "1.0.0.1:1"]);
+        assert_eq!(Vec::from(interleave(v4_only.clone())), v4_only);
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[test]
+    fn staggered_attempts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let mut targets = addrs(&["[::1]:1"]);
+        targets.insert(0, live);
+
+        let start = Instant::now();
+        let stream = run(connect_with(targets, hanging_v6)).unwrap();
+        let elapsed = start.elapsed();
+
+        // IPv6 went first, and IPv4 only got its turn after the delay
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        let delay = Duration::from_millis(ATTEMPT_DELAY_MS);
+        assert!(elapsed >= delay && elapsed < delay * 4, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn fallback_on_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let mut targets = addrs(&["[::1]:1", "[::2]:1"]);
+        targets.push(live);
+
+        // Every refusal starts the next attempt without waiting out the delay
+        let start = Instant::now();
+        let stream = run(connect_with(targets, refused_v6)).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(start.elapsed() < Duration::from_millis(ATTEMPT_DELAY_MS));
+
+        let refused = run(connect_with(addrs(&["[::1]:1", "[::2]:1"]), refused_v6));
+        match refused {
+            Err(::error::Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -4,7 +4,9 @@ use std::fmt;
 use hyper;
 use hyper_tls;
 use serde_json as json;
-use discord::gateway::websocket;
+use discord::gateway;
+use discord::gateway::websocket::{self, CloseCode};
+use discord::proxy::ProxyError;
 use dxgi::Error as DError;
 
 #[derive(Debug)]
@@ -15,8 +17,14 @@ pub enum Error {
     Hyper(hyper::Error),
     Tls(hyper_tls::Error),
     Websocket(websocket::Error),
+    Proxy(ProxyError),
     Graphics(i32),
     Timer(tokio::timer::Error),
+    GatewayClosed(CloseCode, Option<String>),
+    NotConnected,
+    PayloadTooLarge(usize),
+    /// A required environment variable isn't set
+    MissingEnv(&'static str),
     FutureError,
 }
 
@@ -29,8 +37,23 @@ impl fmt::Display for Error {
             Error::Hyper(err) => write!(fmt, "Http error: {}", err),
             Error::Tls(err) => write!(fmt, "Tls error: {}", err),
             Error::Websocket(err) => write!(fmt, "WebSocket error: {}", err),
+            Error::Proxy(err) => write!(fmt, "Proxy error: {}", err),
             Error::Graphics(hr) => write!(fmt, "Graphics error: {:x} {}", hr, DError(*hr)),
             Error::Timer(err) => write!(fmt, "Timer error: {}", err),
+            Error::GatewayClosed(status, reason) => write!(
+                fmt,
+                "Gateway closed the connection: {} {}",
+                status,
+                reason.as_ref().map(|r| &r[..]).unwrap_or("")
+            ),
+            Error::NotConnected => write!(fmt, "Not connected to the gateway"),
+            Error::PayloadTooLarge(size) => write!(
+                fmt,
+                "Tried to send a {} byte payload, the gateway accepts at most {}",
+                size,
+                gateway::MAX_PAYLOAD_SIZE
+            ),
+            Error::MissingEnv(name) => write!(fmt, "Environment variable {} is not set", name),
             Error::FutureError => write!(fmt, "Unspecified futures error"),
         }
     }
@@ -79,6 +102,12 @@ impl From<websocket::Error> for Error {
     }
 }
 
+impl From<ProxyError> for Error {
+    fn from(e: ProxyError) -> Error {
+        Error::Proxy(e)
+    }
+}
+
 impl From<tokio::timer::Error> for Error {
     fn from(e: tokio::timer::Error) -> Error {
         Error::Timer(e)